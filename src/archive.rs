@@ -0,0 +1,326 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Component, Path},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::errors::DocumentError;
+
+/// Archives with more entries than this are refused, they are more likely a zip bomb than scans.
+const MAXIMUM_ENTRIES: usize = 1000;
+/// Limit of the extracted size, counted on the bytes written rather than on the archive's headers.
+const MAXIMUM_EXTRACTED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// What happens with the documents of an archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveMode {
+    /// Ocr every document on its own and upload them keeping the folders of the archive.
+    #[default]
+    Preserve,
+    /// Merge the documents, in the order of their paths, into one pdf and ocr it.
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+/// Extract the regular files of `archive` into `destination`, returning their paths relative to
+/// it sorted. Entries escaping the destination, links and devices are skipped, of entries sharing
+/// a path the last one wins like when tar appends a newer version of a file.
+#[instrument]
+pub async fn extract(
+    archive: &Utf8Path,
+    kind: ArchiveKind,
+    destination: &Utf8Path,
+) -> Result<Vec<Utf8PathBuf>> {
+    let archive = archive.to_owned();
+    let destination = destination.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file =
+            fs::File::open(&archive).wrap_err_with(|| format!("failed to open {archive}"))?;
+        let mut extractor = Extractor::new(&destination);
+        match kind {
+            ArchiveKind::Zip => extract_zip(file, &mut extractor)?,
+            ArchiveKind::Tar => extract_tar(file, &mut extractor)?,
+        }
+        let mut files = extractor.files;
+        files.sort();
+        Ok(files)
+    })
+    .await?
+}
+
+fn extract_zip(file: fs::File, extractor: &mut Extractor) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file).map_err(|err| invalid(err.to_string()))?;
+    if archive.len() > MAXIMUM_ENTRIES {
+        return Err(invalid(format!(
+            "it has {} entries, more than the {MAXIMUM_ENTRIES} allowed",
+            archive.len()
+        )));
+    }
+    for index in 0..archive.len() {
+        let mut entry = archive
+            .by_index(index)
+            .map_err(|err| invalid(err.to_string()))?;
+        if !entry.is_file() || entry.is_symlink() {
+            continue;
+        }
+        let Some(path) = entry.enclosed_name() else {
+            warn!(
+                name = entry.name(),
+                "Skipping an entry outside of the archive"
+            );
+            continue;
+        };
+        extractor.write(&path, &mut entry)?;
+    }
+    Ok(())
+}
+
+fn extract_tar(file: fs::File, extractor: &mut Extractor) -> Result<()> {
+    let mut archive = tar::Archive::new(file);
+    for (index, entry) in archive
+        .entries()
+        .map_err(|err| invalid(err.to_string()))?
+        .enumerate()
+    {
+        if index >= MAXIMUM_ENTRIES {
+            return Err(invalid(format!(
+                "it has more than the {MAXIMUM_ENTRIES} entries allowed"
+            )));
+        }
+        let mut entry = entry.map_err(|err| invalid(err.to_string()))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(|err| invalid(err.to_string()))?
+            .into_owned();
+        extractor.write(&path, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Writes entries below `destination` keeping count of what was written.
+struct Extractor {
+    destination: Utf8PathBuf,
+    files: Vec<Utf8PathBuf>,
+    written: u64,
+    maximum_size: u64,
+}
+
+impl Extractor {
+    fn new(destination: &Utf8Path) -> Self {
+        Self {
+            destination: destination.to_owned(),
+            files: Vec::new(),
+            written: 0,
+            maximum_size: MAXIMUM_EXTRACTED_SIZE,
+        }
+    }
+
+    fn write(&mut self, path: &Path, content: &mut impl Read) -> Result<()> {
+        let Some(relative) = safe_relative_path(path) else {
+            warn!(?path, "Skipping an entry outside of the archive");
+            return Ok(());
+        };
+        // A file can not also be a folder of another entry.
+        if let Some(other) = self.files.iter().find(|file| {
+            *file != &relative && (relative.starts_with(file) || file.starts_with(&relative))
+        }) {
+            return Err(invalid(format!(
+                "the entries {other} and {relative} need a file and a folder of the same name"
+            )));
+        }
+        let replaced = self.files.contains(&relative);
+        if replaced {
+            warn!(%relative, "Replacing an earlier entry with the same path");
+        }
+        let target = self.destination.join(&relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).wrap_err_with(|| format!("failed to create {parent}"))?;
+        }
+        let mut file =
+            fs::File::create(&target).wrap_err_with(|| format!("failed to create {target}"))?;
+        let remaining = self.maximum_size - self.written;
+        let written = io::copy(&mut content.take(remaining + 1), &mut file)
+            .wrap_err_with(|| format!("failed to extract {relative}"))?;
+        if written > remaining {
+            return Err(invalid(format!(
+                "it extracts to more than {} bytes",
+                self.maximum_size
+            )));
+        }
+        self.written += written;
+        if !replaced {
+            self.files.push(relative);
+        }
+        Ok(())
+    }
+}
+
+/// Archives the worker can not read or refuses to extract fail the job for good.
+fn invalid(reason: String) -> Report {
+    DocumentError::InvalidArchive(reason).into()
+}
+
+/// Only plain names are kept, an absolute path or `..` would let an entry escape the
+/// destination.
+fn safe_relative_path(path: &Path) -> Option<Utf8PathBuf> {
+    let mut relative = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name.to_str()?),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!relative.as_str().is_empty()).then_some(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use camino::Utf8PathBuf;
+    use color_eyre::Result;
+    use tempfile::tempdir;
+    use test_case::test_case;
+
+    use super::{extract, ArchiveKind, Extractor, MAXIMUM_ENTRIES};
+    use crate::errors::DocumentError;
+
+    fn is_invalid_archive(err: &color_eyre::Report) -> bool {
+        matches!(
+            err.downcast_ref::<DocumentError>(),
+            Some(DocumentError::InvalidArchive(_))
+        )
+    }
+
+    fn write_tar(archive: &Utf8PathBuf, entries: &[(&str, &[u8])]) -> Result<()> {
+        let mut builder = tar::Builder::new(std::fs::File::create(archive)?);
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *content)?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+
+    #[test_case("scans/receipt.jpg" => Some(Utf8PathBuf::from("scans/receipt.jpg")))]
+    #[test_case("./receipt.jpg" => Some(Utf8PathBuf::from("receipt.jpg")))]
+    #[test_case("../../etc/passwd" => None; "parent of the destination")]
+    #[test_case("scans/../../passwd" => None)]
+    #[test_case("/etc/passwd" => None; "absolute path")]
+    fn safe_relative_path(path: &str) -> Option<Utf8PathBuf> {
+        super::safe_relative_path(Path::new(path))
+    }
+
+    #[tokio::test]
+    async fn extract_zip_skips_escaping_entries() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let archive = dir.join("bundle.zip");
+        {
+            let mut writer = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+            let options = zip::write::SimpleFileOptions::default();
+            writer.start_file("2024/b.pdf", options)?;
+            writer.write_all(b"%PDF-1.7")?;
+            writer.start_file("../escape.pdf", options)?;
+            writer.write_all(b"%PDF-1.7")?;
+            writer.start_file("a.pdf", options)?;
+            writer.write_all(b"%PDF-1.7")?;
+            writer.finish()?;
+        }
+
+        let destination = dir.join("extracted");
+        let files = extract(&archive, ArchiveKind::Zip, &destination).await?;
+        assert_eq!(files, ["2024/b.pdf", "a.pdf"]);
+        assert!(destination.join("2024/b.pdf").exists());
+        assert!(!dir.join("escape.pdf").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_tar_keeps_the_last_of_duplicate_entries() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let archive = dir.join("bundle.tar");
+        write_tar(
+            &archive,
+            &[
+                ("scans/a.pdf", b"old"),
+                ("b.pdf", b"%PDF-1.7"),
+                ("scans/a.pdf", b"new"),
+            ],
+        )?;
+
+        let destination = dir.join("extracted");
+        let files = extract(&archive, ArchiveKind::Tar, &destination).await?;
+        assert_eq!(files, ["b.pdf", "scans/a.pdf"]);
+        assert_eq!(std::fs::read(destination.join("scans/a.pdf"))?, b"new");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_tar_refuses_a_file_that_is_also_a_folder() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let archive = dir.join("bundle.tar");
+        write_tar(
+            &archive,
+            &[("scans", b"%PDF-1.7"), ("scans/a.pdf", b"%PDF-1.7")],
+        )?;
+
+        let result = extract(&archive, ArchiveKind::Tar, &dir.join("extracted")).await;
+        assert!(is_invalid_archive(&result.unwrap_err()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_zip_refuses_too_many_entries() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let archive = dir.join("bundle.zip");
+        {
+            let mut writer = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+            for index in 0..=MAXIMUM_ENTRIES {
+                writer.start_file(
+                    format!("{index}.pdf"),
+                    zip::write::SimpleFileOptions::default(),
+                )?;
+            }
+            writer.finish()?;
+        }
+
+        let result = extract(&archive, ArchiveKind::Zip, &dir.join("extracted")).await;
+        assert!(is_invalid_archive(&result.unwrap_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn extractor_refuses_more_than_the_maximum_size() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let mut extractor = Extractor {
+            maximum_size: 10,
+            ..Extractor::new(&dir)
+        };
+        extractor.write(Path::new("a.pdf"), &mut &b"%PDF-1.7"[..])?;
+        let err = extractor
+            .write(Path::new("b.pdf"), &mut &b"%PDF-1.7"[..])
+            .unwrap_err();
+        assert!(is_invalid_archive(&err));
+        assert_eq!(extractor.files, ["a.pdf"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::HashMap, time::Duration};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::ocr::{Document, OcrOutput};
+
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+/// Prefix of the directories of the stages, names taken from the payload like the one of the
+/// download only ever appear inside of them so they can not collide with each other or with the
+/// checkpoint.
+const STAGE_PREFIX: &str = "stage-";
+/// Work areas untouched for this long belong to jobs that were finished or given up on elsewhere.
+const STALE_WORK_AREA_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What every stage of a job produced so far, a retried job starts from the first stage that
+/// did not finish.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub downloaded: Option<Utf8PathBuf>,
+    /// Documents found in the download, converted or merged into pdfs when needed.
+    #[serde(default)]
+    pub documents: Option<Vec<Document>>,
+    /// Ocr results keyed by the path of their document inside of the archive, the document of a
+    /// download that is not an archive or of a merged one has the empty key.
+    #[serde(default)]
+    pub ocred: HashMap<String, OcrOutput>,
+    /// Files already on drive, keyed by the name of the local file they were uploaded from.
+    #[serde(default)]
+    pub uploaded: HashMap<String, UploadedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadedFile {
+    pub id: Option<String>,
+    pub web_view_link: Option<String>,
+}
+
+/// Directory holding the files of a job between its attempts. It is keyed by the job id inside of
+/// the work dir, so only attempts on workers sharing the work dir resume, the others start over.
+#[derive(Debug)]
+pub struct WorkArea {
+    dir: Utf8PathBuf,
+    checkpoint: Checkpoint,
+}
+
+impl WorkArea {
+    #[instrument]
+    pub async fn open(work_dir: &Utf8Path, job_id: Uuid) -> Result<Self> {
+        let dir = work_dir.join(job_id.to_string());
+        fs::create_dir_all(&dir)
+            .await
+            .wrap_err_with(|| format!("failed to create work area {dir}"))?;
+        let checkpoint = match fs::read(dir.join(CHECKPOINT_FILE)).await {
+            Ok(content) => {
+                let checkpoint: Checkpoint =
+                    serde_json::from_slice(&content).wrap_err("invalid checkpoint")?;
+                info!(?checkpoint, "Resuming job");
+                checkpoint
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Checkpoint::default(),
+            Err(err) => return Err(err).wrap_err("failed to read checkpoint"),
+        };
+        Ok(Self { dir, checkpoint })
+    }
+
+    pub fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+
+    /// Directory the `stage` of the job writes its files to.
+    pub fn stage_dir(&self, stage: &str) -> Utf8PathBuf {
+        self.dir.join(format!("{STAGE_PREFIX}{stage}"))
+    }
+
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    /// Change the checkpoint and persist it, written to a temporary file first so a crash never
+    /// leaves a half written checkpoint behind.
+    pub async fn save<F>(&mut self, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut Checkpoint),
+    {
+        update(&mut self.checkpoint);
+        let content = serde_json::to_vec(&self.checkpoint)?;
+        let temporary = self.dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+        fs::write(&temporary, content)
+            .await
+            .wrap_err("failed to write checkpoint")?;
+        fs::rename(&temporary, self.dir.join(CHECKPOINT_FILE))
+            .await
+            .wrap_err("failed to save checkpoint")
+    }
+
+    /// Remove the work area of a job that is not going to be attempted again.
+    #[instrument]
+    pub async fn discard(work_dir: &Utf8Path, job_id: Uuid) -> Result<()> {
+        let dir = work_dir.join(job_id.to_string());
+        match fs::remove_dir_all(&dir).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).wrap_err_with(|| format!("failed to remove work area {dir}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument]
+    pub async fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)
+            .await
+            .wrap_err("failed to clean up directory")
+    }
+}
+
+/// Remove the work areas nobody touched for a week, left behind by attempts that were retried on
+/// another worker or by workers that died.
+#[instrument]
+pub async fn remove_stale_work_areas(work_dir: &Utf8Path) -> Result<()> {
+    let mut entries = match fs::read_dir(work_dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("failed to list {work_dir}")),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let modified = entry.metadata().await?.modified()?;
+        if modified.elapsed().unwrap_or_default() < STALE_WORK_AREA_AGE {
+            continue;
+        }
+        info!(?path, "Removing stale work area");
+        if let Err(err) = fs::remove_dir_all(&path).await {
+            warn!(?err, ?path, "Failed to remove stale work area");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use color_eyre::Result;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    use super::{UploadedFile, WorkArea};
+
+    #[tokio::test]
+    async fn checkpoint_survives_reopening() -> Result<()> {
+        let work_dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let job_id = Uuid::now_v7();
+
+        let mut work_area = WorkArea::open(&work_dir, job_id).await?;
+        assert!(work_area.checkpoint().downloaded.is_none());
+        let downloaded = work_area.stage_dir("download").join("scan.pdf");
+        work_area
+            .save(|checkpoint| {
+                checkpoint.downloaded = Some(downloaded.clone());
+                checkpoint.uploaded.insert(
+                    "scan.pdf".to_string(),
+                    UploadedFile {
+                        id: Some("abc".to_string()),
+                        web_view_link: None,
+                    },
+                );
+            })
+            .await?;
+
+        let work_area = WorkArea::open(&work_dir, job_id).await?;
+        assert_eq!(work_area.checkpoint().downloaded, Some(downloaded));
+        assert!(work_area.checkpoint().uploaded.contains_key("scan.pdf"));
+
+        let dir = work_area.dir().to_owned();
+        work_area.remove().await?;
+        assert!(!dir.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use camino::Utf8Path;
+use clap::ValueEnum;
+use color_eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::DocumentError,
+    layout::{LayoutFile, LayoutFormat},
+    limits::ResourceLimits,
+    profile::{OcrOptions, OutputType},
+};
+
+mod fake;
+mod ocrmypdf;
+#[cfg(feature = "tesseract")]
+mod tesseract;
+
+pub use self::ocrmypdf::ExitCode;
+
+/// Programs able to ocr a pdf, picked per profile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum EngineKind {
+    /// ocrmypdf with all of its preprocessing and pdf/a output.
+    #[default]
+    Ocrmypdf,
+    /// Tesseract linked into the worker, pages are rasterized with pdftoppm. Only the language is
+    /// used from the profile, preprocessing options are refused, and the output is a plain pdf.
+    Tesseract,
+    /// Copies the pdf and writes a fixed text, for tests.
+    Fake,
+}
+
+impl EngineKind {
+    /// Only ocrmypdf converts into pdf/a, the other engines write plain pdfs.
+    pub fn writes_pdfa(self) -> bool {
+        self == Self::Ocrmypdf
+    }
+
+    /// Only ocrmypdf straightens, cleans and upsamples the pages and keeps the text of some of
+    /// them, the other engines recognize every page as it is.
+    pub fn preprocesses(self) -> bool {
+        self == Self::Ocrmypdf
+    }
+
+    /// The fake engine does not recognize anything, it needs no tesseract languages.
+    pub fn uses_tesseract(self) -> bool {
+        self != Self::Fake
+    }
+}
+
+/// What an engine is asked to do, the file names are decided by the caller so every engine
+/// produces the same layout.
+#[derive(Debug)]
+pub struct OcrRequest<'a> {
+    pub input: &'a Utf8Path,
+    pub pdf: &'a Utf8Path,
+    /// Where the text goes, `None` when the profile turned it off.
+    pub sidecar: Option<&'a Utf8Path>,
+    /// Tesseract languages e.g. `deu+eng`.
+    pub language: &'a str,
+    pub options: &'a OcrOptions,
+    /// Limits every subprocess of the engine is started with.
+    pub limits: &'a ResourceLimits,
+    /// Formats of its recognition the engine writes next to the pdf when it can, the caller
+    /// falls back to recognizing the pages again for the others.
+    pub recognition: &'a [LayoutFormat],
+    /// Scratch directory for intermediate files.
+    pub work_dir: &'a Utf8Path,
+}
+
+#[derive(Debug, Default)]
+pub struct EngineOutput {
+    /// Name and version of the program that did the ocr e.g. `ocrmypdf 15.4.0`.
+    pub version: String,
+    /// The pdf was written but could not be converted to pdf/a.
+    pub pdfa_conversion_failed: bool,
+    /// What the engine reported about the pages it straightened, empty when it does not tell.
+    pub corrections: Vec<PageCorrection>,
+    /// The requested recognition formats the engine wrote, with the pages it recognized.
+    pub recognition: Vec<LayoutFile>,
+}
+
+impl EngineOutput {
+    pub fn recognition(&self, format: LayoutFormat) -> Option<&Utf8Path> {
+        self.recognition
+            .iter()
+            .find(|file| file.format == format)
+            .map(|file| file.path.as_path())
+    }
+}
+
+/// How a page was straightened before it was recognized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PageCorrection {
+    /// Page number starting at 1.
+    pub page: usize,
+    /// Orientation detected on the page in degrees, clockwise.
+    pub rotation: Option<u16>,
+    /// Angle in degrees the page was deskewed by.
+    pub deskew: Option<f32>,
+}
+
+/// Turns a pdf into a searchable pdf and optionally a text sidecar.
+#[async_trait]
+pub trait OcrEngine: Debug + Send + Sync {
+    /// Output type used when the profile does not choose one.
+    fn default_output_type(&self) -> OutputType;
+
+    async fn ocr(&self, request: &OcrRequest<'_>) -> Result<EngineOutput>;
+}
+
+/// What a job does when its engine failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Retrying can not help, the job fails for good.
+    Fail,
+    /// The pdf can not be ocred, it is uploaded as it is.
+    UploadAsIs,
+    /// The pdf already has text, ocr it again skipping those pages.
+    SkipText,
+    /// Something outside of the document failed, the job is retried later.
+    Retry,
+}
+
+/// Failures of the document and the known exit codes are handled, anything else is retried.
+pub fn recovery(err: &Report) -> Recovery {
+    if err.downcast_ref::<DocumentError>().is_some() {
+        return Recovery::Fail;
+    }
+    err.downcast_ref::<ExitCode>()
+        .map_or(Recovery::Retry, |exit_code| exit_code.recovery())
+}
+
+pub fn engine(kind: EngineKind) -> Result<Box<dyn OcrEngine>> {
+    match kind {
+        EngineKind::Ocrmypdf => Ok(Box::new(ocrmypdf::Ocrmypdf)),
+        #[cfg(feature = "tesseract")]
+        EngineKind::Tesseract => Ok(Box::new(tesseract::Tesseract)),
+        #[cfg(not(feature = "tesseract"))]
+        EngineKind::Tesseract => Err(color_eyre::eyre::eyre!(
+            "the tesseract engine is not available, build with the tesseract feature"
+        )),
+        EngineKind::Fake => Ok(Box::new(fake::Fake)),
+    }
+}
@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::fs;
+
+use super::{EngineOutput, OcrEngine, OcrRequest};
+use crate::profile::OutputType;
+
+/// Text the fake engine "recognizes" on every document.
+pub const FAKE_TEXT: &str = "Fake ocr text";
+
+/// Deterministic engine that leaves the pdf as it is and writes a fixed text, so the pipeline can
+/// be tested without ocrmypdf or tesseract.
+#[derive(Debug)]
+pub struct Fake;
+
+#[async_trait]
+impl OcrEngine for Fake {
+    fn default_output_type(&self) -> OutputType {
+        OutputType::Pdf
+    }
+
+    async fn ocr(&self, request: &OcrRequest<'_>) -> Result<EngineOutput> {
+        fs::copy(request.input, request.pdf)
+            .await
+            .wrap_err_with(|| format!("failed to copy {}", request.input))?;
+        if let Some(sidecar) = request.sidecar {
+            let text = format!("{FAKE_TEXT} ({})\n\x0c", request.language);
+            fs::write(sidecar, text)
+                .await
+                .wrap_err("failed to write sidecar file")?;
+        }
+        Ok(EngineOutput {
+            version: "fake".to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::{Utf8Path, Utf8PathBuf};
+    use color_eyre::Result;
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    use super::{Fake, FAKE_TEXT};
+    use crate::{
+        engine::{OcrEngine, OcrRequest},
+        limits::ResourceLimits,
+        profile::OcrOptions,
+    };
+
+    #[tokio::test]
+    async fn fake_engine_is_deterministic() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let pdf = dir.join("test.pdf");
+        let sidecar = dir.join("test.txt");
+        let request = OcrRequest {
+            input: Utf8Path::new("fixtures/test.pdf"),
+            pdf: &pdf,
+            sidecar: Some(&sidecar),
+            language: "deu",
+            options: &OcrOptions::default(),
+            limits: &ResourceLimits::default(),
+            recognition: &[],
+            work_dir: &dir,
+        };
+
+        let output = Fake.ocr(&request).await?;
+        assert_eq!(output.version, "fake");
+        assert_eq!(fs::read("fixtures/test.pdf").await?, fs::read(&pdf).await?);
+        assert_eq!(
+            fs::read_to_string(&sidecar).await?,
+            format!("{FAKE_TEXT} (deu)\n\x0c")
+        );
+        Ok(())
+    }
+}
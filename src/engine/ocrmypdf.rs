@@ -0,0 +1,322 @@
+use std::{collections::BTreeMap, process::ExitStatus};
+
+use async_trait::async_trait;
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result, Section, SectionExt,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{fs, process::Command};
+use tracing::{info, info_span, instrument, Instrument};
+
+use super::{EngineOutput, OcrEngine, OcrRequest, PageCorrection, Recovery};
+use crate::{
+    layout::{self, LayoutFile, LayoutFormat},
+    limits::ResourceLimits,
+    profile::OutputType,
+};
+
+/// hOCR without pages, for documents ocrmypdf did not recognize any page of.
+const EMPTY_HOCR: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<html \
+                          xmlns=\"http://www.w3.org/1999/xhtml\">\n <head>\n  <title></title>\n \
+                          </head>\n <body>\n </body>\n</html>\n";
+
+lazy_static! {
+    static ref ORIENTATION_REGEX: Regex =
+        Regex::new(r"^\s*(\d+)\s+page is facing (\S+),").expect("invalid regex");
+    static ref DESKEW_REGEX: Regex =
+        Regex::new(r"^\s*(\d+)\s+[Dd]eskew angle:?\s*(-?\d+(?:\.\d+)?)").expect("invalid regex");
+}
+
+/// Exit codes documented by ocrmypdf, every failure of the engine carries one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitCode {
+    #[error("ocrmypdf was called with invalid arguments")]
+    BadArgs,
+    #[error("the input is not a valid pdf")]
+    InputFile,
+    #[error("a program ocrmypdf depends on is missing")]
+    MissingDependency,
+    #[error("ocrmypdf produced an invalid pdf")]
+    InvalidOutputPdf,
+    #[error("ocrmypdf could not read or write a file")]
+    FileAccessError,
+    #[error("the pdf already has text")]
+    AlreadyDoneOcr,
+    #[error("a program called by ocrmypdf failed")]
+    ChildProcessError,
+    #[error("the pdf is encrypted")]
+    EncryptedPdf,
+    #[error("the tesseract configuration is invalid")]
+    InvalidConfig,
+    #[error("the pdf could not be converted to pdf/a")]
+    PdfaConversionFailed,
+    #[error("ocrmypdf failed")]
+    OtherError,
+    #[error("ocrmypdf was interrupted")]
+    Interrupted,
+    #[error("ocrmypdf exited with the unknown code {0}")]
+    Unknown(i32),
+}
+
+impl ExitCode {
+    /// `None` when ocrmypdf succeeded, a process killed by a signal was interrupted.
+    fn from_status(status: ExitStatus) -> Option<Self> {
+        let code = match status.code() {
+            Some(0) => return None,
+            Some(1) => Self::BadArgs,
+            Some(2) => Self::InputFile,
+            Some(3) => Self::MissingDependency,
+            Some(4) => Self::InvalidOutputPdf,
+            Some(5) => Self::FileAccessError,
+            Some(6) => Self::AlreadyDoneOcr,
+            Some(7) => Self::ChildProcessError,
+            Some(8) => Self::EncryptedPdf,
+            Some(9) => Self::InvalidConfig,
+            Some(10) => Self::PdfaConversionFailed,
+            Some(15) => Self::OtherError,
+            Some(130) | None => Self::Interrupted,
+            Some(code) => Self::Unknown(code),
+        };
+        Some(code)
+    }
+
+    pub fn recovery(self) -> Recovery {
+        match self {
+            Self::BadArgs | Self::InputFile | Self::InvalidOutputPdf | Self::InvalidConfig => {
+                Recovery::Fail
+            }
+            Self::EncryptedPdf => Recovery::UploadAsIs,
+            Self::AlreadyDoneOcr => Recovery::SkipText,
+            // A missing dependency is fixed by deploying again, the job itself is fine. A signal
+            // may come from the rlimits but also from a worker shutting down, only running out
+            // of the time the pages get fails the job.
+            Self::MissingDependency
+            | Self::Interrupted
+            | Self::FileAccessError
+            | Self::ChildProcessError
+            | Self::PdfaConversionFailed
+            | Self::OtherError
+            | Self::Unknown(_) => Recovery::Retry,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Ocrmypdf;
+
+#[async_trait]
+impl OcrEngine for Ocrmypdf {
+    fn default_output_type(&self) -> OutputType {
+        OutputType::Pdfa
+    }
+
+    async fn ocr(&self, request: &OcrRequest<'_>) -> Result<EngineOutput> {
+        let mut arguments = vec!["-l".to_string(), request.language.to_string()];
+        arguments.extend(request.options.arguments()?);
+        // The deskew angles are only logged at the debug level.
+        if request.options.json_result() {
+            arguments.extend(["--verbose".to_string(), "1".to_string()]);
+        }
+        if let Some(jobs) = request.limits.jobs {
+            arguments.extend(["--jobs".to_string(), jobs.to_string()]);
+        }
+        // The hOCR renderer leaves tesseract's hOCR of every recognized page in ocrmypdf's
+        // temporary files, they are kept inside of the work dir to pick them up.
+        let hocr = request.recognition.contains(&LayoutFormat::Hocr);
+        let temporary_dir = request.work_dir.join("ocrmypdf-temporary");
+        if hocr {
+            arguments.extend([
+                "--pdf-renderer".to_string(),
+                "hocr".to_string(),
+                "--keep-temporary-files".to_string(),
+            ]);
+            if temporary_dir.exists() {
+                fs::remove_dir_all(&temporary_dir).await?;
+            }
+            fs::create_dir_all(&temporary_dir).await?;
+        }
+        arguments.push(request.input.to_string());
+        if let Some(sidecar) = request.sidecar {
+            arguments.extend(["--sidecar".to_string(), sidecar.to_string()]);
+        }
+        arguments.push(request.pdf.to_string());
+        let mut command = Command::new("ocrmypdf");
+        command.args(&arguments);
+        if hocr {
+            command.env("TMPDIR", &temporary_dir);
+        }
+        let output = request
+            .limits
+            .output(&mut command)
+            .instrument(info_span!("ocrmypdf", ?arguments))
+            .await
+            .wrap_err("failed call spawn ocrmypdf")?;
+
+        // The pdf is still valid when only its pdf/a conversion failed.
+        let exit_code = ExitCode::from_status(output.status);
+        let pdfa_conversion_failed =
+            exit_code == Some(ExitCode::PdfaConversionFailed) && request.pdf.exists();
+        let failure = exit_code.filter(|_| !pdfa_conversion_failed);
+        let mut recognition = Vec::new();
+        if hocr && failure.is_none() {
+            let hocr_file = request.pdf.with_extension(LayoutFormat::Hocr.extension());
+            collect_hocr(&temporary_dir, &hocr_file).await?;
+            recognition.push(LayoutFile {
+                format: LayoutFormat::Hocr,
+                path: hocr_file,
+            });
+        }
+        if hocr {
+            fs::remove_dir_all(&temporary_dir).await?;
+        }
+        let Some(exit_code) = failure else {
+            return Ok(EngineOutput {
+                version: version(request.limits).await?,
+                pdfa_conversion_failed,
+                corrections: parse_corrections(&String::from_utf8_lossy(&output.stderr)),
+                recognition,
+            });
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Err(Report::new(exit_code)
+            .with_section(|| output.status.to_string().header("Status code:"))
+            .with_section(|| stderr.trim().to_string().header("Stderr:"))
+            .with_section(|| stdout.trim().to_string().header("Stdout:")))
+    }
+}
+
+/// Merge the hOCR ocrmypdf kept of the pages it recognized e.g. `000002_ocr_hocr.hocr` into
+/// `hocr_file`, pages it skipped have none.
+async fn collect_hocr(temporary_dir: &Utf8Path, hocr_file: &Utf8Path) -> Result<()> {
+    let mut pages = Vec::new();
+    let mut work_dirs = fs::read_dir(temporary_dir).await?;
+    while let Some(work_dir) = work_dirs.next_entry().await? {
+        let mut entries = fs::read_dir(work_dir.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(number) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix("_ocr_hocr.hocr"))
+                .and_then(|number| number.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            let hocr = fs::read_to_string(entry.path())
+                .await
+                .wrap_err("failed to read the hocr of a page")?;
+            pages.push((number, hocr));
+        }
+    }
+    pages.sort_by_key(|(number, _)| *number);
+    info!(pages = pages.len(), "Collected the hocr of ocrmypdf");
+    if pages.is_empty() {
+        // Every page was skipped, an empty document keeps the result consistent.
+        fs::write(hocr_file, EMPTY_HOCR).await?;
+        return Ok(());
+    }
+    fs::write(hocr_file, layout::merge_hocr(&pages)?)
+        .await
+        .wrap_err_with(|| format!("failed to write {hocr_file}"))
+}
+
+/// Best effort, ocrmypdf logs the orientation of every page it looked at e.g.
+/// `   2 page is facing ⇨, confidence 11.53 - will rotate` and with `--verbose` the angle it
+/// deskewed it by e.g. `   2 Deskew angle: 1.250`.
+fn parse_corrections(log: &str) -> Vec<PageCorrection> {
+    let mut corrections = BTreeMap::<usize, PageCorrection>::new();
+    for line in log.lines() {
+        if let Some(captures) = ORIENTATION_REGEX.captures(line) {
+            let page = captures[1].parse().unwrap_or_default();
+            let rotation = match &captures[2] {
+                "⇧" => 0,
+                "⇨" => 90,
+                "⇩" => 180,
+                "⇦" => 270,
+                _ => continue,
+            };
+            corrections.entry(page).or_default().rotation = Some(rotation);
+        } else if let Some(captures) = DESKEW_REGEX.captures(line) {
+            let page = captures[1].parse().unwrap_or_default();
+            corrections.entry(page).or_default().deskew = captures[2].parse().ok();
+        }
+    }
+    corrections
+        .into_iter()
+        .map(|(page, correction)| PageCorrection { page, ..correction })
+        .collect()
+}
+
+#[instrument(skip(limits), ret)]
+async fn version(limits: &ResourceLimits) -> Result<String> {
+    let output = limits
+        .output(Command::new("ocrmypdf").arg("--version"))
+        .await
+        .wrap_err("failed call spawn ocrmypdf")?;
+    if !output.status.success() {
+        return Err(eyre!("failed to get the ocrmypdf version"));
+    }
+    let version = String::from_utf8_lossy(&output.stdout);
+    Ok(format!("ocrmypdf {}", version.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use test_case::test_case;
+
+    use super::{parse_corrections, ExitCode};
+    use crate::engine::{PageCorrection, Recovery};
+
+    #[test_case(0 => None)]
+    #[test_case(2 => Some(ExitCode::InputFile))]
+    #[test_case(6 => Some(ExitCode::AlreadyDoneOcr))]
+    #[test_case(8 => Some(ExitCode::EncryptedPdf))]
+    #[test_case(42 => Some(ExitCode::Unknown(42)))]
+    fn from_status(code: i32) -> Option<ExitCode> {
+        ExitCode::from_status(ExitStatus::from_raw(code << 8))
+    }
+
+    #[test]
+    fn killed_is_interrupted() {
+        // SIGKILL, e.g. from the memory limit or the timeout.
+        let status = ExitStatus::from_raw(9);
+        assert_eq!(ExitCode::from_status(status), Some(ExitCode::Interrupted));
+    }
+
+    #[test]
+    fn corrections_from_the_log() {
+        let log = "Scanning contents: 100%\n   1 page is facing ⇧, confidence 6.19 - rotation appears correct\n   1 Deskew angle: -0.500\n   2 page is facing ⇨, confidence 11.53 - will rotate\n";
+        assert_eq!(
+            parse_corrections(log),
+            [
+                PageCorrection {
+                    page: 1,
+                    rotation: Some(0),
+                    deskew: Some(-0.5),
+                },
+                PageCorrection {
+                    page: 2,
+                    rotation: Some(90),
+                    deskew: None,
+                },
+            ]
+        );
+    }
+
+    #[test_case(ExitCode::InputFile => Recovery::Fail)]
+    #[test_case(ExitCode::EncryptedPdf => Recovery::UploadAsIs)]
+    #[test_case(ExitCode::AlreadyDoneOcr => Recovery::SkipText)]
+    #[test_case(ExitCode::ChildProcessError => Recovery::Retry)]
+    #[test_case(ExitCode::Interrupted => Recovery::Retry)]
+    fn recovery(code: ExitCode) -> Recovery {
+        code.recovery()
+    }
+}
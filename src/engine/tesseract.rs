@@ -0,0 +1,163 @@
+use std::ffi::{CStr, CString};
+
+use async_trait::async_trait;
+use camino::Utf8Path;
+use color_eyre::{eyre::eyre, Result};
+use tesseract_sys::{
+    TessAltoRendererCreate, TessBaseAPI, TessBaseAPICreate, TessBaseAPIDelete, TessBaseAPIEnd,
+    TessBaseAPIGetDatapath, TessBaseAPIInit3, TessBaseAPIProcessPages, TessDeleteResultRenderer,
+    TessHOcrRendererCreate, TessPDFRendererCreate, TessResultRenderer, TessResultRendererInsert,
+    TessTextRendererCreate, TessVersion,
+};
+use tokio::fs;
+use tracing::{info_span, instrument, Instrument};
+
+use super::{EngineOutput, OcrEngine, OcrRequest};
+use crate::{
+    layout::{self, LayoutFile, LayoutFormat},
+    limits::TIMEOUT_PER_PAGE,
+    profile::OutputType,
+};
+
+/// Tesseract called through its C api. Pages are rasterized with pdftoppm and rendered into a pdf
+/// with an invisible text layer by tesseract's own pdf renderer, the hOCR and ALTO of the same
+/// recognition are written by its renderers when requested. The recognition runs in the
+/// worker's process so the rlimits only apply to pdftoppm, and a timed out or cancelled job leaves
+/// it running on its blocking thread until it finishes, tesseract gives up on every page taking
+/// longer than the time a page gets so that is bounded too. The preprocessing options are
+/// refused before the engine runs, see [crate::profile::OcrOptions::check_engine].
+#[derive(Debug)]
+pub struct Tesseract;
+
+#[async_trait]
+impl OcrEngine for Tesseract {
+    fn default_output_type(&self) -> OutputType {
+        OutputType::Pdf
+    }
+
+    #[instrument(skip(self))]
+    async fn ocr(&self, request: &OcrRequest<'_>) -> Result<EngineOutput> {
+        let pages_dir = request.work_dir.join("pages");
+        let pages = layout::rasterize(request.input, &pages_dir, request.limits).await?;
+        let page_list = pages_dir.join("pages.txt");
+        layout::write_page_list(&pages, &page_list).await?;
+
+        // The renderers append their extension to the output base.
+        let output_base = request.pdf.with_extension("");
+        let text = request.sidecar.is_some();
+        let language = request.language.to_string();
+        let base = output_base.clone();
+        let formats = request.recognition.to_vec();
+        tokio::task::spawn_blocking(move || render(&page_list, &base, &language, text, &formats))
+            .instrument(info_span!("tesseract"))
+            .await??;
+        if let Some(sidecar) = request.sidecar {
+            let text_file = output_base.with_extension("txt");
+            if text_file != sidecar {
+                fs::rename(&text_file, sidecar).await?;
+            }
+        }
+        Ok(EngineOutput {
+            version: version(),
+            recognition: request
+                .recognition
+                .iter()
+                .map(|format| LayoutFile {
+                    format: *format,
+                    path: output_base.with_extension(format.extension()),
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+}
+
+fn version() -> String {
+    // SAFETY: TessVersion returns a pointer to a static nul terminated string.
+    let version = unsafe { CStr::from_ptr(TessVersion()) };
+    format!("tesseract {}", version.to_string_lossy())
+}
+
+/// Owns a tesseract instance, ended and freed on drop.
+struct Api(*mut TessBaseAPI);
+
+impl Drop for Api {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from TessBaseAPICreate and is not used after this.
+        unsafe {
+            TessBaseAPIEnd(self.0);
+            TessBaseAPIDelete(self.0);
+        }
+    }
+}
+
+/// Owns the first renderer of a chain, tesseract deletes the inserted ones with it.
+struct Renderer(*mut TessResultRenderer);
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from a renderer constructor and is not used after this.
+        unsafe { TessDeleteResultRenderer(self.0) }
+    }
+}
+
+fn render(
+    page_list: &Utf8Path,
+    output_base: &Utf8Path,
+    language: &str,
+    text: bool,
+    formats: &[LayoutFormat],
+) -> Result<()> {
+    let page_list = CString::new(page_list.as_str())?;
+    let output_base = CString::new(output_base.as_str())?;
+    let language = CString::new(language)?;
+
+    // SAFETY: every pointer handed to tesseract is either a nul terminated string that outlives
+    // the call or an object created by tesseract and owned by the guards above.
+    unsafe {
+        let api = Api(TessBaseAPICreate());
+        if TessBaseAPIInit3(api.0, std::ptr::null(), language.as_ptr()) != 0 {
+            return Err(eyre!(
+                "failed to initialize tesseract for {}",
+                language.to_string_lossy()
+            ));
+        }
+        // The pdf renderer embeds the glyph-less font shipped with the traineddata.
+        let datapath = TessBaseAPIGetDatapath(api.0);
+        let renderer = Renderer(TessPDFRendererCreate(output_base.as_ptr(), datapath, 0));
+        if renderer.0.is_null() {
+            return Err(eyre!("failed to create the pdf renderer"));
+        }
+        if text {
+            let text_renderer = TessTextRendererCreate(output_base.as_ptr());
+            if text_renderer.is_null() {
+                return Err(eyre!("failed to create the text renderer"));
+            }
+            TessResultRendererInsert(renderer.0, text_renderer);
+        }
+        for format in formats {
+            let layout_renderer = match format {
+                LayoutFormat::Hocr => TessHOcrRendererCreate(output_base.as_ptr()),
+                LayoutFormat::Alto => TessAltoRendererCreate(output_base.as_ptr()),
+            };
+            if layout_renderer.is_null() {
+                return Err(eyre!("failed to create the {format:?} renderer"));
+            }
+            TessResultRendererInsert(renderer.0, layout_renderer);
+        }
+        let page_timeout = i32::try_from(TIMEOUT_PER_PAGE.as_millis()).unwrap_or(i32::MAX);
+        if TessBaseAPIProcessPages(
+            api.0,
+            page_list.as_ptr(),
+            std::ptr::null(),
+            page_timeout,
+            renderer.0,
+        ) == 0
+        {
+            return Err(eyre!("tesseract failed to process the pages"));
+        }
+        // Renderers only finish writing their files when they are deleted.
+        drop(renderer);
+    }
+    Ok(())
+}
@@ -1,18 +1,110 @@
+use std::time::Duration;
+
 use thiserror::Error;
 use warp::reject::Reject;
 
+use crate::engine::{self, ExitCode, Recovery};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("access denied")]
     AccessDenied,
+    #[error("failed to open the job's work area")]
+    Checkpoint(#[source] color_eyre::Report),
     #[error("failed to process the input file")]
     Orc(#[source] color_eyre::Report),
+    #[error("failed to load the key settings")]
+    Settings(#[source] color_eyre::Report),
+    #[error("failed to render the output templates")]
+    Output(#[source] color_eyre::Report),
+    #[error("failed to save the job status")]
+    Status(#[source] color_eyre::Report),
     #[error("failed to cleanup")]
     Cleanup(#[source] color_eyre::Report),
     #[error("failed to upload")]
     Upload(#[source] color_eyre::Report),
     #[error("failed to save into the queue")]
     Queue(#[source] color_eyre::Report),
+    #[error("invalid ocr options")]
+    Options(#[source] color_eyre::Report),
+}
+
+/// Failures caused by the document or the options it was sent with, retrying can not fix them.
+#[derive(Debug, Error)]
+pub enum DocumentError {
+    #[error("unsupported input, expected a pdf, an office document, an image or an archive")]
+    Unsupported,
+    #[error("the archive has no supported documents")]
+    NoDocuments,
+    #[error("libreoffice could not convert {0} into a pdf")]
+    OfficeConversion(String),
+    #[error("the image {0} could not be converted into a pdf")]
+    ImageConversion(String),
+    #[error("invalid language {language:?} in {selection:?}")]
+    InvalidLanguage { language: String, selection: String },
+    #[error("languages {0:?} are not installed")]
+    MissingLanguages(Vec<String>),
+    #[error("the {engine} engine only writes plain pdfs, not {output_type}")]
+    OutputType { engine: String, output_type: String },
+    #[error("the {engine} engine can not honour the options {options:?}")]
+    UnsupportedOptions {
+        engine: String,
+        options: Vec<String>,
+    },
+    #[error("{0} took longer than {1:?}")]
+    Timeout(String, Duration),
+    #[error("the ocred pdf does not conform to {0}")]
+    Conformance(String),
+    #[error("invalid options, {0}")]
+    InvalidOptions(String),
+    #[error("invalid archive, {0}")]
+    InvalidArchive(String),
+    #[error("unknown variables {variables:?} in template {template:?}")]
+    UnknownVariables {
+        template: String,
+        variables: Vec<String>,
+    },
+    #[error("the filename template {0:?} renders folders, use the folder template for them")]
+    FilenameTemplate(String),
+}
+
+impl Error {
+    /// Failures of the document or of its options are not retried, the ocr also knows which of
+    /// the engine's failures retrying can not fix.
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::Orc(err) => engine::recovery(err),
+            Error::Options(_) => Recovery::Fail,
+            Error::Output(err) if err.downcast_ref::<DocumentError>().is_some() => Recovery::Fail,
+            _ => Recovery::Retry,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<ExitCode> {
+        match self {
+            Error::Orc(err) => err.downcast_ref::<ExitCode>().copied(),
+            _ => None,
+        }
+    }
+
+    /// The message of the error and of its causes, without the sections of the report.
+    pub fn reason(&self) -> String {
+        let cause = match self {
+            Error::AccessDenied => return self.to_string(),
+            Error::Checkpoint(err)
+            | Error::Orc(err)
+            | Error::Settings(err)
+            | Error::Output(err)
+            | Error::Status(err)
+            | Error::Cleanup(err)
+            | Error::Upload(err)
+            | Error::Queue(err)
+            | Error::Options(err) => err,
+        };
+        cause.chain().fold(self.to_string(), |reason, cause| {
+            format!("{reason}: {cause}")
+        })
+    }
 }
 
 impl Reject for Error {}
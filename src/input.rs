@@ -0,0 +1,432 @@
+use std::{
+    io::{BufReader, BufWriter},
+    time::Duration,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result, Section, SectionExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs, fs::File, io::AsyncReadExt, process::Command, task::spawn_blocking, time::timeout,
+};
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+use crate::{archive::ArchiveKind, errors::DocumentError, limits::ResourceLimits};
+
+/// Brands of the iso media container used by heic/heif images.
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+/// Names of the first zip entry of office documents, odf requires `mimetype` to come first and
+/// office usually writes its content types first.
+const OFFICE_ZIP_ENTRIES: [&[u8]; 2] = [b"mimetype", b"[Content_Types].xml"];
+/// Folders only found in office open xml documents, for zips whose first entry is another one.
+const OFFICE_ZIP_FOLDERS: [&str; 3] = ["word/", "xl/", "ppt/"];
+/// Enough of the file to see the name of the first zip entry and the magic of a tar header.
+const HEADER_SIZE: usize = 512;
+/// Where the `ustar` magic sits in a tar header.
+const TAR_MAGIC_OFFSET: usize = 257;
+/// Converting office documents can hang on broken files. The time does not grow with the
+/// document, running out of it is retried like the other fixed timeouts.
+const OFFICE_CONVERSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Kinds of files accepted as input, told apart by their first bytes since the names of phone
+/// photos are not reliable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputKind {
+    Pdf,
+    Jpeg,
+    Png,
+    Tiff,
+    Heic,
+    /// Word processor, spreadsheet or presentation documents like docx, odt, xlsx or rtf.
+    Office,
+    Archive(ArchiveKind),
+}
+
+impl InputKind {
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        match header {
+            [b'%', b'P', b'D', b'F', b'-', ..] => Some(Self::Pdf),
+            [0xff, 0xd8, 0xff, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some(Self::Png),
+            [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => Some(Self::Tiff),
+            [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..]
+                if brand.len() >= 4 && HEIF_BRANDS.contains(&&brand[..4]) =>
+            {
+                Some(Self::Heic)
+            }
+            [b'P', b'K', 3, 4, ..] if is_office_zip(header) => Some(Self::Office),
+            // The compound file of the legacy formats like doc and xls.
+            [0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1, ..] => Some(Self::Office),
+            [b'{', b'\\', b'r', b't', b'f', ..] => Some(Self::Office),
+            // Every other zip, including empty ones, is a bundle of documents.
+            [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => {
+                Some(Self::Archive(ArchiveKind::Zip))
+            }
+            _ if header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(&b"ustar"[..]) => {
+                Some(Self::Archive(ArchiveKind::Tar))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether the entries listed in the central directory of a zip make up an office document.
+fn is_office_archive<'a>(mut names: impl Iterator<Item = &'a str>) -> bool {
+    names.any(|name| {
+        OFFICE_ZIP_ENTRIES.contains(&name.as_bytes())
+            || OFFICE_ZIP_FOLDERS
+                .iter()
+                .any(|folder| name.starts_with(folder))
+    })
+}
+
+/// Look at every entry of the zip at `path`, a zip that can not be read is left to the archive
+/// extraction to report.
+async fn is_office_zip_file(path: &Utf8Path) -> Result<bool> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path).wrap_err_with(|| format!("failed to open {path}"))?;
+        Ok(match zip::ZipArchive::new(file) {
+            Ok(archive) => is_office_archive(archive.file_names()),
+            Err(err) => {
+                warn!(?err, "Failed to read the zip central directory");
+                false
+            }
+        })
+    })
+    .await?
+}
+
+/// The name of the first entry of a zip comes right after its 30 bytes local header.
+fn is_office_zip(header: &[u8]) -> bool {
+    let Some(name_length) = header.get(26..28) else {
+        return false;
+    };
+    let name_length = u16::from_le_bytes([name_length[0], name_length[1]]) as usize;
+    header
+        .get(30..30 + name_length)
+        .is_some_and(|name| OFFICE_ZIP_ENTRIES.contains(&name))
+}
+
+/// Tell what kind of input `path` is from its first bytes.
+#[instrument(ret)]
+pub async fn detect_input(path: &Utf8Path) -> Result<InputKind> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    File::open(path)
+        .await
+        .wrap_err_with(|| format!("failed to open {path}"))?
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut header)
+        .await
+        .wrap_err("failed to read the file header")?;
+    let kind = InputKind::detect(&header)
+        .ok_or_else(|| Report::new(DocumentError::Unsupported))
+        .with_section(|| hex::encode(&header).header("Header:"))?;
+    // The header only shows the first entry, office documents do not have to start with theirs.
+    if kind == InputKind::Archive(ArchiveKind::Zip) && is_office_zip_file(path).await? {
+        return Ok(InputKind::Office);
+    }
+    Ok(kind)
+}
+
+/// Turn a document into a pdf ocrmypdf accepts, images and office documents are converted into
+/// `input_dir` and pdfs are used as they are. The converters run under the ocr's `limits`.
+#[instrument(skip(limits))]
+pub async fn convert_to_pdf(
+    path: &Utf8Path,
+    kind: InputKind,
+    input_dir: &Utf8Path,
+    limits: &ResourceLimits,
+) -> Result<Utf8PathBuf> {
+    match kind {
+        InputKind::Pdf => return Ok(path.to_owned()),
+        InputKind::Archive(_) => return Err(eyre!("{path} is an archive, not a document")),
+        _ => {}
+    }
+    info!(?kind, "Converting into a pdf");
+    fs::create_dir_all(input_dir).await?;
+    let stem = path.file_stem().unwrap_or("input");
+    let pdf = input_dir.join(format!("{stem}.pdf"));
+    if kind == InputKind::Office {
+        convert_office_document(path, input_dir, limits).await?;
+        // libreoffice exits successfully when it can not load the document, it just writes nothing.
+        if !fs::try_exists(&pdf).await? {
+            return Err(DocumentError::OfficeConversion(path.to_string()).into());
+        }
+        return Ok(pdf);
+    }
+    // A converter refusing the image is the image's fault, one that can not be started is not.
+    let refused = |_: &Command| Report::new(DocumentError::ImageConversion(path.to_string()));
+    let image = match kind {
+        // img2pdf does not read heic, the primary image is extracted with its orientation applied.
+        InputKind::Heic => {
+            let jpeg = input_dir.join(format!("{stem}.jpg"));
+            run_failing_with(
+                Command::new("heif-convert").args([path.as_str(), jpeg.as_str()]),
+                limits,
+                refused,
+            )
+            .instrument(info_span!("heif_convert"))
+            .await?;
+            jpeg
+        }
+        // img2pdf refuses images with transparency.
+        InputKind::Png => {
+            let flattened = input_dir.join(format!("{stem}-flattened.png"));
+            let (source, target) = (path.to_owned(), flattened.clone());
+            if spawn_blocking(move || flatten_png(&source, &target)).await?? {
+                flattened
+            } else {
+                path.to_owned()
+            }
+        }
+        _ => path.to_owned(),
+    };
+    // img2pdf embeds the images without recompressing them, takes their dpi from the file and
+    // turns pages according to the exif orientation. Every page of a tiff becomes a pdf page.
+    run_failing_with(
+        Command::new("img2pdf").args([
+            "--rotation=ifvalid",
+            "--output",
+            pdf.as_str(),
+            image.as_str(),
+        ]),
+        limits,
+        refused,
+    )
+    .instrument(info_span!("img2pdf"))
+    .await?;
+    Ok(pdf)
+}
+
+/// Write `source` without its transparency to `target`, laid onto a white page like a viewer
+/// shows it. Returns false without writing anything when the png is opaque.
+fn flatten_png(source: &Utf8Path, target: &Utf8Path) -> Result<bool> {
+    let invalid = |err: png::DecodingError| {
+        Report::new(DocumentError::ImageConversion(source.to_string()))
+            .with_section(|| err.to_string().header("Error:"))
+    };
+    let file = std::fs::File::open(source).wrap_err_with(|| format!("failed to open {source}"))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    // Palettes and transparent colors are expanded into an alpha channel.
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(invalid)?;
+    let pixel_dims = reader.info().pixel_dims;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(invalid)?;
+    let (color_type, channels) = match frame.color_type {
+        png::ColorType::GrayscaleAlpha => (png::ColorType::Grayscale, 1),
+        png::ColorType::Rgba => (png::ColorType::Rgb, 3),
+        _ => return Ok(false),
+    };
+    let pixels = buffer[..frame.buffer_size()]
+        .chunks_exact(channels + 1)
+        .flat_map(|pixel| {
+            let (color, alpha) = pixel.split_at(channels);
+            let alpha = u16::from(alpha[0]);
+            color
+                .iter()
+                .map(move |value| ((u16::from(*value) * alpha + 255 * (255 - alpha)) / 255) as u8)
+        })
+        .collect::<Vec<_>>();
+
+    let file =
+        std::fs::File::create(target).wrap_err_with(|| format!("failed to create {target}"))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), frame.width, frame.height);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    // img2pdf sizes the page from the resolution.
+    encoder.set_pixel_dims(pixel_dims);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(true)
+}
+
+/// Concatenate `pdfs` into `output` in their order.
+#[instrument(skip(limits))]
+pub async fn merge_pdfs(
+    pdfs: &[Utf8PathBuf],
+    output: &Utf8Path,
+    limits: &ResourceLimits,
+) -> Result<()> {
+    if let [pdf] = pdfs {
+        fs::copy(pdf, output)
+            .await
+            .wrap_err_with(|| format!("failed to copy {pdf}"))?;
+        return Ok(());
+    }
+    run(Command::new("pdfunite").args(pdfs).arg(output), limits)
+        .instrument(info_span!("pdfunite"))
+        .await
+}
+
+/// Convert with a headless libreoffice that gets a profile of its own, thrown away with the job,
+/// and an empty environment so the worker's settings and secrets are not handed to it. It is not
+/// isolated any further, it runs as the worker's user with its network. It runs under the ocr's
+/// `limits` and a timeout, which kill libreoffice with every helper it started.
+#[instrument(skip(limits))]
+async fn convert_office_document(
+    path: &Utf8Path,
+    output_dir: &Utf8Path,
+    limits: &ResourceLimits,
+) -> Result<()> {
+    let profile = output_dir.join("libreoffice-profile");
+    let mut command = Command::new("soffice");
+    command
+        .env_clear()
+        .env("HOME", profile.as_str())
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .arg(format!("-env:UserInstallation=file://{profile}"))
+        .args([
+            "--headless",
+            "--norestore",
+            "--nolockcheck",
+            "--convert-to",
+            "pdf",
+            "--outdir",
+            output_dir.as_str(),
+            path.as_str(),
+        ]);
+    timeout(OFFICE_CONVERSION_TIMEOUT, run(&mut command, limits))
+        .instrument(info_span!("soffice"))
+        .await
+        .map_err(|_| eyre!("converting {path} took longer than {OFFICE_CONVERSION_TIMEOUT:?}"))?
+}
+
+async fn run(command: &mut Command, limits: &ResourceLimits) -> Result<()> {
+    run_failing_with(command, limits, |command| eyre!("{command:?} failed")).await
+}
+
+/// Run `command` under `limits`, when it fails the error made by `failure` gets its status and
+/// stderr.
+async fn run_failing_with(
+    command: &mut Command,
+    limits: &ResourceLimits,
+    failure: impl FnOnce(&Command) -> Report,
+) -> Result<()> {
+    let output = limits
+        .output(command)
+        .await
+        .wrap_err_with(|| format!("failed to spawn {command:?}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(failure(command)
+            .with_section(|| output.status.to_string().header("Status code:"))
+            .with_section(|| stderr.trim().to_string().header("Stderr:")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use camino::Utf8PathBuf;
+    use color_eyre::Result;
+    use tempfile::tempdir;
+    use test_case::test_case;
+
+    use super::{detect_input, InputKind};
+    use crate::archive::ArchiveKind;
+
+    #[test_case(b"%PDF-1.7\n" => Some(InputKind::Pdf))]
+    #[test_case(&[0xff, 0xd8, 0xff, 0xe1, 0, 0] => Some(InputKind::Jpeg))]
+    #[test_case(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR" => Some(InputKind::Png))]
+    #[test_case(b"II*\0\x08\0\0\0" => Some(InputKind::Tiff))]
+    #[test_case(b"MM\0*\0\0\0\x08" => Some(InputKind::Tiff))]
+    #[test_case(b"\0\0\0\x18ftypheic\0\0\0\0" => Some(InputKind::Heic))]
+    #[test_case(b"\0\0\0\x18ftypisom\0\0\0\0" => None)]
+    #[test_case(b"PK\x03\x04\x14\0\0\0\x08\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x08\0\0\0mimetypeapplication/vnd.oasis.opendocument.text" => Some(InputKind::Office))]
+    #[test_case(b"PK\x03\x04\x14\0\x06\0\x08\0\0\0!\0\0\0\0\0\0\0\0\0\0\0\0\0\x13\0\0\0[Content_Types].xml" => Some(InputKind::Office))]
+    #[test_case(b"PK\x03\x04\x14\0\0\0\x08\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x09\0\0\0scan1.pdf" => Some(InputKind::Archive(ArchiveKind::Zip)))]
+    #[test_case(b"{\\rtf1\\ansi" => Some(InputKind::Office))]
+    #[test_case(b"PK\x03\x04" => Some(InputKind::Archive(ArchiveKind::Zip)))]
+    #[test_case(b"PK\x05\x06\0\0" => Some(InputKind::Archive(ArchiveKind::Zip)))]
+    #[test_case(b"" => None)]
+    fn detect(header: &[u8]) -> Option<InputKind> {
+        InputKind::detect(header)
+    }
+
+    #[test_case(&["_rels/.rels", "docProps/app.xml", "word/document.xml"] => true)]
+    #[test_case(&["_rels/.rels", "xl/workbook.xml"] => true)]
+    #[test_case(&["META-INF/manifest.xml", "mimetype"] => true)]
+    #[test_case(&["scan1.pdf", "words/scan2.pdf"] => false)]
+    #[test_case(&[] => false)]
+    fn is_office_archive(names: &[&str]) -> bool {
+        super::is_office_archive(names.iter().copied())
+    }
+
+    #[tokio::test]
+    async fn detect_docx_not_starting_with_content_types() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let docx = dir.join("letter.docx");
+        {
+            let mut writer = zip::ZipWriter::new(std::fs::File::create(&docx)?);
+            let options = zip::write::SimpleFileOptions::default();
+            writer.start_file("_rels/.rels", options)?;
+            writer.write_all(b"<Relationships/>")?;
+            writer.start_file("word/document.xml", options)?;
+            writer.write_all(b"<w:document/>")?;
+            writer.start_file("[Content_Types].xml", options)?;
+            writer.write_all(b"<Types/>")?;
+            writer.finish()?;
+        }
+        assert_eq!(detect_input(&docx).await?, InputKind::Office);
+        Ok(())
+    }
+
+    fn write_png(path: &Utf8PathBuf, color_type: png::ColorType, pixels: &[u8]) -> Result<()> {
+        let mut encoder = png::Encoder::new(std::fs::File::create(path)?, 2, 1);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn flatten_png_onto_white() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let source = dir.join("logo.png");
+        let target = dir.join("logo-flattened.png");
+        write_png(&source, png::ColorType::Rgba, &[255, 0, 0, 255, 0, 0, 0, 0])?;
+        assert!(super::flatten_png(&source, &target)?);
+
+        let mut reader = png::Decoder::new(std::fs::File::open(&target)?).read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels)?;
+        assert_eq!(frame.color_type, png::ColorType::Rgb);
+        assert_eq!(pixels, [255, 0, 0, 255, 255, 255]);
+        Ok(())
+    }
+
+    #[test]
+    fn flatten_png_keeps_opaque_ones() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let source = dir.join("scan.png");
+        let target = dir.join("scan-flattened.png");
+        write_png(&source, png::ColorType::Grayscale, &[0, 255])?;
+        assert!(!super::flatten_png(&source, &target)?);
+        assert!(!target.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_tar() {
+        let mut header = [0; 512];
+        header[257..262].copy_from_slice(b"ustar");
+        assert_eq!(
+            InputKind::detect(&header),
+            Some(InputKind::Archive(ArchiveKind::Tar))
+        );
+    }
+}
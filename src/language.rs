@@ -0,0 +1,206 @@
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result, Section, SectionExt,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use tokio::process::Command;
+use tracing::{info, info_span, instrument, Instrument};
+
+use crate::{errors::DocumentError, limits::ResourceLimits};
+
+pub const DEFAULT_LANGUAGE: &str = "eng";
+
+lazy_static! {
+    /// A tesseract language like `deu` or a script variant like `chi_sim`.
+    static ref LANGUAGE_CODE_REGEX: Regex =
+        Regex::new(r"^[a-z]{3}(?:_[a-z]+)*$").expect("invalid regex");
+}
+/// Resolution used to rasterize the sample page, enough for tesseract to recognize the script.
+const SAMPLE_RESOLUTION: &str = "150";
+
+/// Guess the language of a pdf by ocring its first page and running a language identifier on
+/// the text. Returns `None` when the guess is unreliable or the language is not installed.
+#[instrument(skip(limits))]
+pub async fn detect_language(
+    pdf: &Utf8Path,
+    work_dir: &Utf8Path,
+    limits: &ResourceLimits,
+) -> Result<Option<String>> {
+    let installed = installed_languages(limits).await?;
+    let sample_prefix = work_dir.join("language-sample");
+    run(
+        Command::new("pdftoppm").args([
+            "-f",
+            "1",
+            "-l",
+            "1",
+            "-r",
+            SAMPLE_RESOLUTION,
+            "-png",
+            "-singlefile",
+            pdf.as_str(),
+            sample_prefix.as_str(),
+        ]),
+        limits,
+    )
+    .instrument(info_span!("pdftoppm"))
+    .await?;
+    let sample = sample_prefix.with_extension("png");
+
+    let osd = run(
+        Command::new("tesseract").args([sample.as_str(), "stdout", "--psm", "0"]),
+        limits,
+    )
+    .instrument(info_span!("tesseract_osd"))
+    .await
+    .unwrap_or_default();
+    let script_language = parse_osd_script(&osd)
+        .and_then(script_language)
+        .filter(|language| installed.iter().any(|l| l == language))
+        .unwrap_or(DEFAULT_LANGUAGE);
+
+    let text = run(
+        Command::new("tesseract").args([sample.as_str(), "stdout", "-l", script_language]),
+        limits,
+    )
+    .instrument(info_span!("tesseract_sample"))
+    .await?;
+    let detected = identify_language(&text);
+    info!(script_language, ?detected, "Detected language");
+    Ok(detected.filter(|language| installed.contains(language)))
+}
+
+/// Check a tesseract language selection like `deu+eng` before handing it to ocrmypdf.
+pub fn validate_languages(selection: &str, installed: &[String]) -> Result<()> {
+    let languages = selection.split('+').collect::<Vec<_>>();
+    if let Some(invalid) = languages
+        .iter()
+        .find(|language| !LANGUAGE_CODE_REGEX.is_match(language))
+    {
+        return Err(DocumentError::InvalidLanguage {
+            language: invalid.to_string(),
+            selection: selection.to_string(),
+        }
+        .into());
+    }
+    let missing = languages
+        .into_iter()
+        .filter(|language| !installed.iter().any(|l| l == language))
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(Report::new(DocumentError::MissingLanguages(missing))
+            .with_section(|| installed.join(", ").header("Installed languages:")));
+    }
+    Ok(())
+}
+
+/// Languages tesseract has traineddata for.
+#[instrument(skip(limits), ret)]
+pub async fn installed_languages(limits: &ResourceLimits) -> Result<Vec<String>> {
+    let output = run(Command::new("tesseract").arg("--list-langs"), limits).await?;
+    Ok(parse_installed_languages(&output))
+}
+
+/// Run `command` under `limits`, the caller bounds how long it may take.
+async fn run(command: &mut Command, limits: &ResourceLimits) -> Result<String> {
+    let output = limits
+        .output(command)
+        .await
+        .wrap_err_with(|| format!("failed to spawn {command:?}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{command:?} failed")
+            .with_section(|| output.status.to_string().header("Status code:"))
+            .with_section(|| stderr.trim().to_string().header("Stderr:")));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The first line of `tesseract --list-langs` is a header.
+fn parse_installed_languages(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|language| !language.is_empty() && *language != "osd")
+        .map(String::from)
+        .collect()
+}
+
+fn parse_osd_script(osd: &str) -> Option<&str> {
+    osd.lines()
+        .find_map(|line| line.strip_prefix("Script:"))
+        .map(str::trim)
+}
+
+/// Language used for the sample pass of each script tesseract's osd can report.
+fn script_language(script: &str) -> Option<&'static str> {
+    match script {
+        "Latin" => Some("eng"),
+        "Cyrillic" => Some("rus"),
+        "Greek" => Some("ell"),
+        "Arabic" => Some("ara"),
+        "Hebrew" => Some("heb"),
+        "Han" => Some("chi_sim"),
+        "Japanese" => Some("jpn"),
+        "Hangul" => Some("kor"),
+        "Devanagari" => Some("hin"),
+        "Thai" => Some("tha"),
+        _ => None,
+    }
+}
+
+/// whatlang uses ISO 639-3 codes which are what tesseract uses for most languages.
+fn identify_language(text: &str) -> Option<String> {
+    let info = whatlang::detect(text)?;
+    if !info.is_reliable() {
+        return None;
+    }
+    let language = match info.lang() {
+        whatlang::Lang::Cmn => "chi_sim",
+        language => language.code(),
+    };
+    Some(language.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    #[test]
+    fn parse_installed_languages() {
+        let output = "List of available languages in \"/usr/share/tesseract-ocr/5/tessdata/\" (4):\ndeu\neng\nosd\npor\n";
+        assert_eq!(
+            super::parse_installed_languages(output),
+            vec!["deu", "eng", "por"]
+        );
+    }
+
+    #[test_case("deu" => true)]
+    #[test_case("deu+eng" => true)]
+    #[test_case("chi_sim+eng" => true)]
+    #[test_case("fra" => false)]
+    #[test_case("deu+" => false)]
+    #[test_case("../eng" => false)]
+    fn validate_languages(selection: &str) -> bool {
+        let installed = ["chi_sim", "deu", "eng"].map(String::from);
+        super::validate_languages(selection, &installed).is_ok()
+    }
+
+    #[test_case("Page number: 0\nOrientation in degrees: 0\nScript: Latin\nScript confidence: 2.5" => Some("Latin"))]
+    #[test_case("Too few characters. Skipping this page" => None)]
+    fn parse_osd_script(osd: &str) -> Option<&str> {
+        super::parse_osd_script(osd)
+    }
+
+    #[test_case("Sehr geehrte Damen und Herren, hiermit kündige ich meinen Vertrag fristgerecht zum nächstmöglichen Zeitpunkt." => Some("deu".to_string()))]
+    #[test_case("Prezados senhores, venho por meio desta solicitar o cancelamento do meu contrato o mais breve possível." => Some("por".to_string()))]
+    #[test_case("Dear Sir or Madam, I am writing to cancel my contract with your company at the earliest possible date. Please confirm the cancellation and the date on which it takes effect in writing." => Some("eng".to_string()))]
+    #[test_case("Dear Sir or Madam" => None)]
+    fn identify_language(text: &str) -> Option<String> {
+        super::identify_language(text)
+    }
+}
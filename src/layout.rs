@@ -0,0 +1,208 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result, Section, SectionExt,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, process::Command};
+use tracing::{info, info_span, instrument, Instrument};
+
+use crate::limits::ResourceLimits;
+
+lazy_static! {
+    static ref HOCR_PAGE_NUMBER_REGEX: Regex =
+        Regex::new(r"ppageno \d+").expect("invalid regex");
+    /// Ids of the page and its elements start with the page number e.g. `word_1_12`.
+    static ref HOCR_ID_REGEX: Regex =
+        Regex::new(r"id='(page|block|par|line|word)_1([_'])").expect("invalid regex");
+}
+
+/// Resolution the pages are rasterized at, what tesseract is trained for. The coordinates of the
+/// layout files are pixels at this resolution.
+pub const RESOLUTION: u32 = 300;
+
+/// Formats with the coordinates of every recognized word, for tools highlighting search hits on
+/// the page images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutFormat {
+    /// hOCR, html with the boxes in the title attributes.
+    Hocr,
+    /// ALTO xml, the format of libraries and archives.
+    Alto,
+}
+
+impl LayoutFormat {
+    /// Name of tesseract's config writing this format.
+    fn config(self) -> &'static str {
+        match self {
+            Self::Hocr => "hocr",
+            Self::Alto => "alto",
+        }
+    }
+
+    /// Extension tesseract gives the file.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Hocr => "hocr",
+            Self::Alto => "xml",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutFile {
+    pub format: LayoutFormat,
+    pub path: Utf8PathBuf,
+}
+
+/// Rasterize the pages of `pdf` into `pages_dir`, returning the images in page order.
+#[instrument(skip(limits))]
+pub async fn rasterize(
+    pdf: &Utf8Path,
+    pages_dir: &Utf8Path,
+    limits: &ResourceLimits,
+) -> Result<Vec<Utf8PathBuf>> {
+    fs::create_dir_all(pages_dir).await?;
+    let mut command = Command::new("pdftoppm");
+    command
+        .args(["-r", &RESOLUTION.to_string(), "-png"])
+        .arg(pdf)
+        .arg(pages_dir.join("page"));
+    let output = limits
+        .output(&mut command)
+        .instrument(info_span!("pdftoppm"))
+        .await
+        .wrap_err("failed call spawn pdftoppm")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("pdftoppm failed")
+            .with_section(|| output.status.to_string().header("Status code:"))
+            .with_section(|| stderr.trim().to_string().header("Stderr:")));
+    }
+
+    // pdftoppm pads the page numbers so the names sort in page order.
+    let mut pages = Vec::new();
+    let mut entries = fs::read_dir(pages_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = Utf8PathBuf::from_path_buf(entry.path())
+            .map_err(|path| eyre!("invalid page path {path:?}"))?;
+        if path.extension() == Some("png") {
+            pages.push(path);
+        }
+    }
+    pages.sort();
+    info!(pages = pages.len(), "Rasterized pdf");
+    Ok(pages)
+}
+
+/// Write the list of `pages` tesseract reads as one multi page document.
+pub async fn write_page_list(pages: &[Utf8PathBuf], page_list: &Utf8Path) -> Result<()> {
+    let list = pages
+        .iter()
+        .map(|page| format!("{page}\n"))
+        .collect::<String>();
+    fs::write(page_list, list)
+        .await
+        .wrap_err("failed to write the page list")
+}
+
+/// Recognize the pages of the ocred `pdf` again with tesseract to write the `formats` next to it.
+/// This is the fallback for formats the engine does not write itself, like ALTO from ocrmypdf or
+/// anything for a pdf kept as it is. The pages are the ones of the output so the coordinates
+/// match what is uploaded, even after ocrmypdf rotated or deskewed them.
+#[instrument(skip(limits))]
+pub async fn render(
+    pdf: &Utf8Path,
+    language: &str,
+    formats: &[LayoutFormat],
+    limits: &ResourceLimits,
+) -> Result<Vec<LayoutFile>> {
+    if formats.is_empty() {
+        return Ok(Vec::new());
+    }
+    let pages_dir = pdf.with_extension("layout");
+    if pages_dir.exists() {
+        fs::remove_dir_all(&pages_dir).await?;
+    }
+    let pages = rasterize(pdf, &pages_dir, limits).await?;
+    let page_list = pages_dir.join("pages.txt");
+    write_page_list(&pages, &page_list).await?;
+
+    let output_base = pdf.with_extension("");
+    let mut command = Command::new("tesseract");
+    command
+        .arg(&page_list)
+        .arg(&output_base)
+        .args(["-l", language, "--dpi", &RESOLUTION.to_string()])
+        .args(formats.iter().map(|format| format.config()));
+    let output = limits
+        .output(&mut command)
+        .instrument(info_span!("tesseract"))
+        .await
+        .wrap_err("failed call spawn tesseract")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("tesseract failed to write the layout")
+            .with_section(|| output.status.to_string().header("Status code:"))
+            .with_section(|| stderr.trim().to_string().header("Stderr:")));
+    }
+    fs::remove_dir_all(&pages_dir).await?;
+    Ok(formats
+        .iter()
+        .map(|format| LayoutFile {
+            format: *format,
+            path: output_base.with_extension(format.extension()),
+        })
+        .collect())
+}
+
+/// Combine the hOCR files tesseract wrote for single pages into one document. Every file numbers
+/// its page as the first one, the page numbers in the `pages` are put in their place.
+pub fn merge_hocr(pages: &[(usize, String)]) -> Result<String> {
+    let Some((_, first)) = pages.first() else {
+        return Err(eyre!("no hocr pages to merge"));
+    };
+    let (head, _) = first
+        .split_once("<body>")
+        .ok_or_else(|| eyre!("hocr without a body"))?;
+    let mut merged = format!("{head}<body>");
+    for (number, page) in pages {
+        let body = page
+            .split_once("<body>")
+            .and_then(|(_, body)| body.rsplit_once("</body>"))
+            .map(|(body, _)| body)
+            .ok_or_else(|| eyre!("hocr of page {number} without a body"))?;
+        let body = HOCR_PAGE_NUMBER_REGEX.replace_all(body, format!("ppageno {}", number - 1));
+        let body = HOCR_ID_REGEX.replace_all(&body, format!("id='${{1}}_{number}${{2}}"));
+        merged.push_str(&body);
+    }
+    merged.push_str("</body>\n</html>\n");
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_hocr;
+
+    fn page(words: &str) -> String {
+        format!(
+            "<html>\n <head><title></title></head>\n <body>\n  <div class='ocr_page' id='page_1' \
+             title='image \"x.png\"; bbox 0 0 2480 3508; ppageno 0'>\n   <span class='ocrx_word' \
+             id='word_1_1' title='bbox 1 2 3 4; x_wconf 90'>{words}</span>\n  </div>\n </body>\n</html>\n"
+        )
+    }
+
+    #[test]
+    fn merge_pages() {
+        let merged = merge_hocr(&[(1, page("first")), (3, page("third"))]).unwrap();
+        assert_eq!(merged.matches("<body>").count(), 1);
+        assert!(merged.contains("id='page_3'"));
+        assert!(merged.contains("ppageno 2"));
+        assert!(merged.contains("id='word_3_1'"));
+        assert!(merged.contains("third"));
+    }
+}
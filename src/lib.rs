@@ -1,7 +1,7 @@
 use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
 
 use camino::Utf8PathBuf;
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{eyre::WrapErr, Report, Result};
 use google_drive3::oauth2::ApplicationSecret;
 use hmac::{
     digest::{core_api::CoreWrapper, KeyInit},
@@ -23,17 +23,39 @@ use uuid::Uuid;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use crate::{
+    archive::ArchiveMode,
+    checkpoint::{UploadedFile, WorkArea},
+    engine::{ExitCode, Recovery},
     errors::Error,
-    ocr::{process_input, LANGUAGE_REGEX},
+    limits::ResourceLimits,
+    ocr::{process_input, OcrOutput, LANGUAGE_REGEX},
+    ocr_result::{OcrResult, Timings},
+    output::OutputOptions,
+    pdfa::Conformance,
+    profile::OcrOptions,
     queue::{Message, Queue},
     storage::Redis,
 };
 
+mod archive;
+mod checkpoint;
+mod engine;
 mod errors;
 pub mod generate_key;
+mod input;
+mod language;
+mod layout;
+pub mod limits;
 mod ocr;
+mod ocr_result;
+pub mod output;
+mod pdfa;
+pub mod profile;
 mod queue;
+mod resumable;
+pub mod settings;
 mod storage;
+mod text_layer;
 pub mod tracing_config;
 mod upload;
 pub mod worker;
@@ -47,6 +69,9 @@ pub struct Config {
     pub redis_dsn: Url,
     pub secret_key: String,
     pub google_credentials: ApplicationSecret,
+    /// Where jobs keep their files between attempts.
+    pub work_dir: Utf8PathBuf,
+    pub limits: ResourceLimits,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +79,128 @@ pub struct Payload {
     filename: String,
     path: Utf8PathBuf,
     file_url: Url,
+    /// Tesseract languages e.g. `deu+eng`, wins over the filename suffix.
+    #[serde(default)]
+    language: Option<String>,
+    /// Drive id of the original file, used when replacing it.
+    #[serde(default)]
+    file_id: Option<String>,
+    #[serde(default)]
+    output: OutputOptions,
+    /// Name of the key's ocr profile to use instead of its default one.
+    #[serde(default)]
+    profile: Option<String>,
+    /// Ocr options overriding the ones of the profile.
+    #[serde(default)]
+    options: OcrOptions,
+    /// How the documents of an archive are processed.
+    #[serde(default)]
+    archive: ArchiveMode,
+}
+
+/// Outcome of the last attempt of a job, kept after its work area is removed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum JobStatus {
+    Finished(JobResult),
+    Failed(JobFailure),
+}
+
+/// What a finished job produced.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobResult {
+    documents: Vec<DocumentResult>,
+    files: Vec<UploadedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentResult {
+    /// Path inside of the archive the document came from.
+    archive_path: Option<Utf8PathBuf>,
+    language: String,
+    page_count: usize,
+    engine: String,
+    conformance: Option<Conformance>,
+    /// Why the document was uploaded without ocr.
+    skip_reason: Option<ExitCode>,
+    /// Pages whose existing text was kept.
+    text_layer_pages: usize,
+    /// Mean confidence of the recognized words from 0 to 100.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<f32>,
+    /// Uploaded to the review folder because of its low quality.
+    review: bool,
+    /// Where the text, boxes and confidences of every page are kept when the profile asked for
+    /// them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<ResultSummary>,
+}
+
+/// Reference to the ocr result of a document, the words stay out of the job status.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResultSummary {
+    /// Redis key holding the whole result, expiring with the job status.
+    key: String,
+    page_count: usize,
+    timings: Timings,
+}
+
+/// Jobs failing this many times fail for good, whatever the reason.
+pub(crate) const MAXIMUM_JOB_ATTEMPTS: u64 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobFailure {
+    reason: String,
+    exit_code: Option<ExitCode>,
+    /// How many times the job was tried, starting at 1.
+    attempt: u64,
+    /// Whether the job goes back to the queue.
+    pub(crate) retrying: bool,
+}
+
+impl JobFailure {
+    pub(crate) fn new(err: &Report, attempt: u64) -> Self {
+        let (reason, exit_code, recovery) = match err.downcast_ref::<Error>() {
+            Some(error) => (error.reason(), error.exit_code(), error.recovery()),
+            None => (err.to_string(), None, Recovery::Retry),
+        };
+        Self {
+            reason,
+            exit_code,
+            attempt,
+            retrying: recovery != Recovery::Fail && attempt < MAXIMUM_JOB_ATTEMPTS,
+        }
+    }
+}
+
+impl DocumentResult {
+    /// Save the ocr result of the document `index` under its own key and keep a summary of it.
+    async fn load(output: OcrOutput, job_id: Uuid, index: usize, redis: &Redis) -> Result<Self> {
+        let result = match &output.result {
+            Some(path) => {
+                let result = OcrResult::read(path).await?;
+                let key = redis.set_job_result(job_id, index, &result).await?;
+                Some(ResultSummary {
+                    key,
+                    page_count: result.page_count,
+                    timings: result.timings,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            archive_path: output.archive_path,
+            language: output.language,
+            page_count: output.page_count,
+            engine: output.engine,
+            conformance: output.conformance,
+            skip_reason: output.skip_reason,
+            text_layer_pages: output.text_layer_pages,
+            quality: output.quality,
+            review: output.review,
+            result,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -122,6 +269,11 @@ where
     Q: Queue,
 {
     info!("Queueing request");
+    // The profile is only known to the worker, it checks the options again once merged.
+    if let Err(err) = payload.options.validate() {
+        error!(?err, "Invalid ocr options");
+        return Err(warp::reject::custom(Error::Options(err)));
+    }
     let propagator = TraceContextPropagator::new();
     let mut properties = HashMap::new();
     propagator.inject_context(&Span::current().context(), &mut properties);
@@ -149,42 +301,89 @@ where
 
 #[instrument(skip_all, ret)]
 async fn run_ocr_background(
+    job_id: Uuid,
     claim: Claim,
     payload: Payload,
     config: Arc<Config>,
     redis: Arc<Redis>,
+    cancel: CancellationToken,
 ) -> Result<()> {
     info!(app = %claim.token_id, "Got payload");
 
-    let files = process_input(&payload).await.map_err(Error::Orc)?;
-    let upload_path = payload
-        .path
-        .as_path()
-        .parent()
-        .map(|p| p.join("Done"))
-        .unwrap_or_else(|| payload.path.join("Done"));
-    upload::upload_files(claim, &files, upload_path.as_path(), config, redis)
+    let settings = redis
+        .get_key_settings(claim.token_id)
+        .await
+        .map_err(Error::Settings)?;
+    let mut work_area = WorkArea::open(&config.work_dir, job_id)
+        .await
+        .map_err(Error::Checkpoint)?;
+    let ocr_options = settings
+        .ocr_options(payload.profile.as_deref())
+        .map_err(Error::Settings)?;
+    let ocr_options = payload.options.clone().or(ocr_options);
+    ocr_options.validate().map_err(Error::Options)?;
+    let outputs = process_input(
+        &payload,
+        &ocr_options,
+        &config.limits,
+        &cancel,
+        &mut work_area,
+    )
+    .await
+    .map_err(Error::Orc)?;
+    let options = payload.output.clone().or(settings.output);
+    let uploads =
+        output::plan_uploads(&payload, &options, job_id, &outputs).map_err(Error::Output)?;
+    upload::upload_files(
+        job_id,
+        claim,
+        &uploads,
+        &mut work_area,
+        config,
+        redis.clone(),
+    )
+    .await
+    .map_err(Error::Upload)?;
+    let mut documents = Vec::with_capacity(outputs.len());
+    for (index, output) in outputs.into_iter().enumerate() {
+        documents.push(
+            DocumentResult::load(output, job_id, index, &redis)
+                .await
+                .map_err(Error::Status)?,
+        );
+    }
+    let result = JobResult {
+        documents,
+        files: work_area.checkpoint().uploaded.values().cloned().collect(),
+    };
+    info!(
+        documents = result.documents.len(),
+        review = result
+            .documents
+            .iter()
+            .filter(|document| document.review)
+            .count(),
+        files = result.files.len(),
+        "Job finished"
+    );
+    redis
+        .set_job_status(job_id, &JobStatus::Finished(result))
         .await
-        .map_err(Error::Upload)?;
-    cleanup(files).await.map_err(Error::Cleanup)?;
+        .map_err(Error::Status)?;
+    work_area.remove().await.map_err(Error::Cleanup)?;
     info!(monotonic_counter.success_ocr_call = 1);
     Ok(())
 }
 
-#[instrument]
-async fn cleanup(files: Vec<Utf8PathBuf>) -> Result<()> {
-    match files.first().and_then(|f| f.parent()) {
-        None => Ok(()),
-        Some(folder) => tokio::fs::remove_dir_all(folder)
-            .await
-            .wrap_err("failed to clean up directory"),
-    }
-}
-
-async fn handle_error(_err: Rejection) -> std::result::Result<impl Reply, Infallible> {
+async fn handle_error(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
     info!(monotonic_counter.ocr_error_call = 1);
-    let code = StatusCode::INTERNAL_SERVER_ERROR;
-    let message = "Internal Server Error";
+    let (code, message) = match err.find::<Error>() {
+        Some(error @ Error::Options(_)) => (StatusCode::BAD_REQUEST, error.reason()),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".to_string(),
+        ),
+    };
 
     let json = warp::reply::json(&json!({
         "code": code.as_u16(),
@@ -195,6 +394,32 @@ async fn handle_error(_err: Rejection) -> std::result::Result<impl Reply, Infall
 
 #[cfg(test)]
 mod tests {
+    use color_eyre::{eyre::WrapErr, Report, Result};
+    use test_case::test_case;
+
+    use crate::{
+        errors::{DocumentError, Error},
+        JobFailure, MAXIMUM_JOB_ATTEMPTS,
+    };
+
+    fn ocr_error(err: Report) -> Report {
+        let err: Result<()> = Err(err);
+        Error::Orc(err.wrap_err("failed to process scan.pdf").unwrap_err()).into()
+    }
+
+    #[test_case(ocr_error(DocumentError::NoDocuments.into()), 1 => false)]
+    #[test_case(ocr_error(Report::msg("connection reset")), 1 => true)]
+    #[test_case(ocr_error(Report::msg("connection reset")), MAXIMUM_JOB_ATTEMPTS => false)]
+    #[test_case(Report::msg("redis is down"), MAXIMUM_JOB_ATTEMPTS - 1 => true)]
+    #[test_case(Error::Output(DocumentError::UnknownVariables {
+        template: "{yaer}".to_string(),
+        variables: vec!["yaer".to_string()],
+    }.into()).into(), 1 => false)]
+    #[test_case(Error::Options(DocumentError::InvalidOptions("invalid".to_string()).into()).into(), 1 => false)]
+    fn retrying(err: Report, attempt: u64) -> bool {
+        JobFailure::new(&err, attempt).retrying
+    }
+
     #[tokio::test]
     async fn double_check_ssl_flags() {
         assert_eq!(
@@ -0,0 +1,249 @@
+use std::{
+    future::Future,
+    io,
+    process::{Output, Stdio},
+    time::Duration,
+};
+
+use clap::Args;
+use color_eyre::{eyre::eyre, Report, Result};
+use rlimit::Resource;
+use tokio::{process::Command, select, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::errors::DocumentError;
+
+/// Time every ocr gets no matter how short the document is.
+const BASE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time added for every page, generous for ocrmypdf's preprocessing at 300 dpi.
+pub const TIMEOUT_PER_PAGE: Duration = Duration::from_secs(30);
+/// Programs reading the structure of a pdf or reporting their version do not get more time for
+/// more pages.
+pub const INSPECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on the ocr subprocesses of a worker, so one pathological document can not starve or
+/// hang the others. The memory and cpu limits are rlimits, they hold for every process on its own
+/// and not for the job as a whole, a job running 4 tesseracts can use 4 times the memory limit.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ResourceLimits {
+    #[arg(
+        long,
+        env = "OCR_JOBS",
+        help = "Pages ocrmypdf processes in parallel, defaults to the number of cpus"
+    )]
+    pub jobs: Option<usize>,
+    #[arg(
+        long,
+        env = "OCR_MEMORY_LIMIT",
+        help = "Maximum address space in MiB of each ocr process, every subprocess it starts gets \
+                the whole limit, unlimited by default"
+    )]
+    pub memory_limit: Option<u64>,
+    #[arg(
+        long,
+        env = "OCR_CPU_TIME_LIMIT",
+        help = "Maximum cpu seconds of each ocr process, every subprocess it starts gets the whole \
+                limit, unlimited by default"
+    )]
+    pub cpu_time_limit: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Apply the rlimits to `command` and start it in a process group of its own, which
+    /// [ResourceLimits::output] kills as a whole. The limits are inherited by the processes it
+    /// spawns, each of them gets the whole limit.
+    fn apply(&self, command: &mut Command) {
+        command.kill_on_drop(true);
+        let memory = self.memory_limit.map(|mebibytes| mebibytes * 1024 * 1024);
+        let cpu_time = self.cpu_time_limit;
+        // SAFETY: the closure runs in the forked child before exec and only calls setpgid and
+        // setrlimit, which are async-signal-safe, it does not allocate nor take locks.
+        unsafe {
+            command.pre_exec(move || {
+                // tokio only offers process_group with tokio_unstable.
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(memory) = memory {
+                    rlimit::setrlimit(Resource::AS, memory, memory)?;
+                }
+                if let Some(cpu_time) = cpu_time {
+                    rlimit::setrlimit(Resource::CPU, cpu_time, cpu_time)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Run `command` with the limits applied and collect its output like [Command::output].
+    /// Dropping the future, e.g. on a timeout or when the worker is cancelled, kills every process
+    /// of its group and not only the direct child, so the tesseracts started by ocrmypdf or the
+    /// helpers of libreoffice do not keep running.
+    pub async fn output(&self, command: &mut Command) -> io::Result<Output> {
+        self.apply(command);
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let group = ProcessGroup(child.id().and_then(|id| libc::pid_t::try_from(id).ok()));
+        let output = child.wait_with_output().await;
+        // The leader was reaped, once its group is empty the id can be reused by another one.
+        group.release();
+        output
+    }
+}
+
+/// Kills the process group of a child that was not reaped yet when dropped.
+struct ProcessGroup(Option<libc::pid_t>);
+
+impl ProcessGroup {
+    fn release(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        let Some(group) = self.0 else {
+            return;
+        };
+        // SAFETY: killpg has no memory safety requirements. The leader is not reaped yet, its
+        // group id can not belong to anyone else.
+        unsafe {
+            libc::killpg(group, libc::SIGKILL);
+        }
+    }
+}
+
+/// How long the ocr of a document with `page_count` pages may take.
+fn ocr_timeout(page_count: usize) -> Duration {
+    BASE_TIMEOUT + TIMEOUT_PER_PAGE * u32::try_from(page_count).unwrap_or(u32::MAX)
+}
+
+/// How long a [bounded] future may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deadline {
+    /// The time the ocr of a document with this many pages gets, a document running out of it
+    /// fails for good.
+    Pages(usize),
+    /// A time that does not grow with the document, running out of it says nothing about the
+    /// document so the job is retried.
+    Fixed(Duration),
+}
+
+impl Deadline {
+    pub fn duration(self) -> Duration {
+        match self {
+            Deadline::Pages(page_count) => ocr_timeout(page_count),
+            Deadline::Fixed(duration) => duration,
+        }
+    }
+
+    fn exceeded(self, what: &str) -> Report {
+        let duration = self.duration();
+        match self {
+            Deadline::Pages(_) => DocumentError::Timeout(what.to_string(), duration).into(),
+            Deadline::Fixed(_) => eyre!("{what} took longer than {duration:?}"),
+        }
+    }
+}
+
+/// Run `future` until the `deadline` passes or the job is cancelled, `what` names it in the
+/// error. Dropping the future kills the subprocesses it started.
+pub async fn bounded<T>(
+    what: &str,
+    future: impl Future<Output = Result<T>>,
+    deadline: Deadline,
+    cancel: &CancellationToken,
+) -> Result<T> {
+    select! {
+        output = timeout(deadline.duration(), future) => {
+            output.map_err(|_| deadline.exceeded(what))?
+        }
+        () = cancel.cancelled() => Err(eyre!("{what} was cancelled")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use camino::Utf8PathBuf;
+    use color_eyre::Result;
+    use tempfile::tempdir;
+    use test_case::test_case;
+    use tokio::{fs, process::Command, time::timeout};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{Deadline, ResourceLimits};
+    use crate::engine::{recovery, Recovery};
+
+    #[test_case(0 => Duration::from_secs(60))]
+    #[test_case(1 => Duration::from_secs(90))]
+    #[test_case(100 => Duration::from_secs(3060))]
+    fn ocr_timeout(page_count: usize) -> Duration {
+        super::ocr_timeout(page_count)
+    }
+
+    #[test_case(Deadline::Pages(3) => Recovery::Fail)]
+    #[test_case(Deadline::Fixed(Duration::from_secs(60)) => Recovery::Retry)]
+    fn exceeded(deadline: Deadline) -> Recovery {
+        recovery(&deadline.exceeded("the ocr"))
+    }
+
+    #[tokio::test]
+    async fn cancelled_is_retried() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let pending = std::future::pending::<Result<()>>();
+        let err = super::bounded("the ocr", pending, Deadline::Pages(3), &cancel)
+            .await
+            .unwrap_err();
+        assert_eq!(recovery(&err), Recovery::Retry);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_output_kills_the_process_group() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let pid_file = dir.join("pid");
+        let mut command = Command::new("sh");
+        command.args(["-c", &format!("sleep 60 & echo $! > {pid_file}; wait")]);
+        let limits = ResourceLimits::default();
+        assert!(
+            timeout(Duration::from_millis(500), limits.output(&mut command))
+                .await
+                .is_err()
+        );
+
+        let pid = fs::read_to_string(&pid_file).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Killed processes nobody reaped yet are zombies.
+        let alive = fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+            .await
+            .is_ok_and(|stat| !stat.contains(") Z "));
+        assert!(!alive, "sleep {pid} survived its group");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finished_output_leaves_the_group_alone() -> Result<()> {
+        let dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let pid_file = dir.join("pid");
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            &format!("sleep 60 > /dev/null 2>&1 & echo $! > {pid_file}"),
+        ]);
+        let output = ResourceLimits::default().output(&mut command).await?;
+        assert!(output.status.success());
+
+        let pid = fs::read_to_string(&pid_file).await?;
+        let alive = fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+            .await
+            .is_ok_and(|stat| !stat.contains(") Z "));
+        Command::new("kill").arg(pid.trim()).status().await?;
+        assert!(alive, "sleep {pid} was killed after its leader was reaped");
+        Ok(())
+    }
+}
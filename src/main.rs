@@ -2,13 +2,17 @@ use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
 use color_eyre::{eyre::WrapErr, Result};
 use dotenvy::dotenv;
-use drive_ocr::{generate_key, serve, worker};
+use drive_ocr::{
+    generate_key, limits::ResourceLimits, profile::OcrOptions, serve, settings,
+    settings::KeySettings, worker,
+};
 use google_drive3::oauth2::read_application_secret;
 use opentelemetry::global::shutdown_tracer_provider;
 use tokio::signal::ctrl_c;
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 use url::Url;
+use uuid::Uuid;
 
 #[derive(Debug, Parser)]
 struct Config {
@@ -28,6 +32,14 @@ struct Config {
         help = "Path to google's credentials JSON generated on google's dev console."
     )]
     google_credentials: Utf8PathBuf,
+    #[clap(
+        short,
+        long,
+        env,
+        default_value_t = default_work_dir(),
+        help = "Directory where jobs keep their files between retries, retries only resume on workers sharing it."
+    )]
+    work_dir: Utf8PathBuf,
     #[command(subcommand)]
     command: Command,
 }
@@ -44,7 +56,37 @@ enum Command {
         listen_address: String,
     },
     #[command(about = "Start a worker to process the queue.")]
-    Worker,
+    Worker {
+        #[command(flatten)]
+        limits: ResourceLimits,
+    },
+    #[command(
+        about = "Change the defaults used by a generated key.",
+        long_about = "Change the defaults used by a generated key. Templates can use {year}, {month}, {day}, {parent}, {original_name}, {original_stem}, {language}, {job_id} and {page_count}."
+    )]
+    KeySettings {
+        #[clap(short, long, help = "Token id logged when the key was generated")]
+        token_id: Uuid,
+        #[command(flatten)]
+        settings: KeySettings,
+    },
+    #[command(
+        about = "Create or change an ocr profile of a generated key.",
+        long_about = "Create or change an ocr profile of a generated key. Requests use the key's default profile, set with key-settings --profile, unless they name another one."
+    )]
+    Profile {
+        #[clap(short, long, help = "Token id logged when the key was generated")]
+        token_id: Uuid,
+        #[clap(short, long, help = "Name of the profile")]
+        name: String,
+        #[command(flatten)]
+        options: OcrOptions,
+    },
+}
+
+fn default_work_dir() -> Utf8PathBuf {
+    let temp_dir = Utf8PathBuf::from_path_buf(std::env::temp_dir()).expect("invalid temp dir");
+    temp_dir.join("drive-ocr")
 }
 
 #[tokio::main]
@@ -66,6 +108,8 @@ async fn main() -> Result<()> {
                     config.google_credentials
                 )
             })?,
+        work_dir: config.work_dir.clone(),
+        limits: ResourceLimits::default(),
     };
     match config.command {
         Command::GenerateKey => {
@@ -85,7 +129,7 @@ async fn main() -> Result<()> {
 
             serve(&config.secret_key, listen_address, lib_config, c).await?;
         }
-        Command::Worker => {
+        Command::Worker { limits } => {
             let c = CancellationToken::new();
 
             let token = c.clone();
@@ -95,7 +139,27 @@ async fn main() -> Result<()> {
                 token.cancel();
             });
 
-            worker(lib_config, c).await?;
+            worker(
+                drive_ocr::Config {
+                    limits,
+                    ..lib_config
+                },
+                c,
+            )
+            .await?;
+        }
+        Command::KeySettings { token_id, settings } => {
+            let settings = settings::update_key_settings(token_id, settings, &lib_config).await?;
+            info!(?settings, %token_id, "Key settings saved");
+        }
+        Command::Profile {
+            token_id,
+            name,
+            options,
+        } => {
+            let profile =
+                settings::update_profile(token_id, name.clone(), options, &lib_config).await?;
+            info!(?profile, %token_id, %name, "Profile saved");
         }
     }
     shutdown_tracer_provider();
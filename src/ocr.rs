@@ -1,29 +1,277 @@
+use std::time::{Duration, Instant};
+
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::{
     eyre::{eyre, WrapErr},
-    Result, Section, SectionExt,
+    Report, Result, Section, SectionExt,
 };
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use regex::Regex;
-use tokio::{fs, fs::File, io::AsyncWriteExt, process::Command, spawn};
-use tracing::{info, info_span, instrument, Instrument};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, fs::File, io::AsyncWriteExt, process::Command};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
 
-use crate::Payload;
+use crate::{
+    archive::{self, ArchiveMode},
+    checkpoint::WorkArea,
+    engine::{self, EngineOutput, ExitCode, OcrRequest, Recovery},
+    errors::DocumentError,
+    input::{convert_to_pdf, detect_input, merge_pdfs, InputKind},
+    language::{detect_language, installed_languages, validate_languages, DEFAULT_LANGUAGE},
+    layout::{self, LayoutFile, LayoutFormat},
+    limits::{bounded, Deadline, ResourceLimits, INSPECTION_TIMEOUT},
+    ocr_result::{self, Timings},
+    pdfa::{self, Conformance},
+    profile::{OcrMode, OcrOptions, OutputType, PdfaFallback},
+    text_layer::{self, OcrPlan, PageText, TextLayer},
+    Payload,
+};
 
 lazy_static! {
     pub static ref LANGUAGE_REGEX: Regex =
-        Regex::new(r"\.([a-z]{3})\.pdf$").expect("invalid regex");
+        Regex::new(r"\.([a-z]{3}(?:_[a-z]+)*(?:\+[a-z]{3}(?:_[a-z]+)*)*)\.pdf$")
+            .expect("invalid regex");
+}
+
+/// Files produced by the ocr engine and what is known about them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcrOutput {
+    pub pdf: Utf8PathBuf,
+    /// Text of the pdf, `None` when the profile turned the extraction off.
+    pub sidecar: Option<Utf8PathBuf>,
+    pub language: String,
+    pub page_count: usize,
+    /// Name and version of the program that did the ocr e.g. `ocrmypdf 15.4.0`.
+    pub engine: String,
+    /// Outcome of the pdf/a validation, `None` for plain pdfs.
+    #[serde(default)]
+    pub conformance: Option<Conformance>,
+    /// Path of the document inside of the downloaded archive, `None` when the download was the
+    /// document itself.
+    #[serde(default)]
+    pub archive_path: Option<Utf8PathBuf>,
+    /// Why the pdf was uploaded as it is, without ocr.
+    #[serde(default)]
+    pub skip_reason: Option<ExitCode>,
+    /// Pages whose text was kept from the input instead of ocred.
+    #[serde(default)]
+    pub text_layer_pages: usize,
+    /// Word coordinates in the formats the profile asked for.
+    #[serde(default)]
+    pub layout: Vec<LayoutFile>,
+    /// json with the text, boxes and confidences of every page, when the profile asked for it.
+    #[serde(default)]
+    pub result: Option<Utf8PathBuf>,
+    /// Mean confidence of the recognized words from 0 to 100, only computed when needed.
+    #[serde(default)]
+    pub quality: Option<f32>,
+    /// The quality is below the profile's threshold, the document has to be looked at.
+    #[serde(default)]
+    pub review: bool,
+}
+
+/// What is written next to the ocred pdf once the engine ran.
+#[derive(Debug)]
+struct Companions {
+    layout: Vec<LayoutFile>,
+    result: Option<Utf8PathBuf>,
+    quality: Option<f32>,
+    review: bool,
+}
+
+/// A document to ocr, the downloaded file or one of the files of a downloaded archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document {
+    source: Utf8PathBuf,
+    kind: InputKind,
+    archive_path: Option<Utf8PathBuf>,
+}
+
+impl Document {
+    /// Identifies the document's results in the checkpoint.
+    fn checkpoint_key(&self) -> String {
+        self.archive_path
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    }
 }
 
 #[instrument(skip_all, fields(filename=payload.filename, path=?payload.path))]
-pub async fn process_input(payload: &Payload) -> Result<Vec<Utf8PathBuf>> {
-    let working_dir = spawn(async { tempfile::TempDir::new() })
-        .await?
-        .wrap_err("failed to create temporary directory")?
-        .into_path();
-    let working_dir =
-        Utf8PathBuf::from_path_buf(working_dir).expect("invalid temporary dir created");
+pub async fn process_input(
+    payload: &Payload,
+    options: &OcrOptions,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+    work_area: &mut WorkArea,
+) -> Result<Vec<OcrOutput>> {
+    let origin_file_path = match work_area.checkpoint().downloaded.clone() {
+        Some(path) if path.exists() => {
+            info!(?path, "Reusing downloaded file");
+            path
+        }
+        _ => {
+            let path = download(payload, &work_area.stage_dir("download")).await?;
+            work_area
+                .save(|checkpoint| checkpoint.downloaded = Some(path.clone()))
+                .await?;
+            path
+        }
+    };
+
+    // Extracting and merging an archive is not repeated when its documents are still around.
+    let documents = match work_area.checkpoint().documents.clone() {
+        Some(documents) if documents.iter().all(|document| document.source.exists()) => {
+            info!(documents = documents.len(), "Reusing collected documents");
+            documents
+        }
+        _ => {
+            let documents =
+                collect_documents(payload, &origin_file_path, work_area, limits).await?;
+            work_area
+                .save(|checkpoint| checkpoint.documents = Some(documents.clone()))
+                .await?;
+            documents
+        }
+    };
+    let requested_language = payload
+        .language
+        .as_deref()
+        .or(payload.options.language.as_deref());
+    let mut outputs = Vec::with_capacity(documents.len());
+    for (index, document) in documents.into_iter().enumerate() {
+        let checkpoint_key = document.checkpoint_key();
+        if let Some(output) = work_area.checkpoint().ocred.get(&checkpoint_key) {
+            if output.pdf.exists()
+                && output.sidecar.iter().all(|path| path.exists())
+                && output.layout.iter().all(|file| file.path.exists())
+                && output.result.iter().all(|path| path.exists())
+            {
+                info!(?output, "Reusing ocr results");
+                outputs.push(output.clone());
+                continue;
+            }
+        }
+        let document_dir = work_area.stage_dir("documents").join(index.to_string());
+        if document_dir.exists() {
+            fs::remove_dir_all(&document_dir).await?;
+        }
+        let output_path = document_dir.join("ocr");
+        fs::create_dir_all(&output_path).await?;
+        let pdf_path = convert_to_pdf(
+            &document.source,
+            document.kind,
+            &document_dir.join("input"),
+            limits,
+        )
+        .await
+        .wrap_err_with(|| format!("failed to convert {} into a pdf", document.source))?;
+        let output = process_file(
+            &output_path,
+            &pdf_path,
+            requested_language,
+            options,
+            limits,
+            cancel,
+        )
+        .await
+        .wrap_err_with(|| format!("failed to process {}", document.source))?;
+        let output = if output.review && options.review_retry.unwrap_or_default() {
+            let retry_path = document_dir.join("retry");
+            fs::create_dir_all(&retry_path).await?;
+            retry_low_quality(output, &retry_path, &pdf_path, options, limits, cancel).await?
+        } else {
+            output
+        };
+        let output = OcrOutput {
+            archive_path: document.archive_path,
+            ..output
+        };
+        work_area
+            .save(|checkpoint| {
+                checkpoint.ocred.insert(checkpoint_key, output.clone());
+            })
+            .await?;
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+/// The downloaded file is the document unless it is an archive, then every supported file in it
+/// is one, or all of them merged into one when the payload asks for it.
+#[instrument(skip(payload, work_area, limits))]
+async fn collect_documents(
+    payload: &Payload,
+    origin: &Utf8Path,
+    work_area: &WorkArea,
+    limits: &ResourceLimits,
+) -> Result<Vec<Document>> {
+    let kind = detect_input(origin).await?;
+    let InputKind::Archive(archive_kind) = kind else {
+        return Ok(vec![Document {
+            source: origin.to_owned(),
+            kind,
+            archive_path: None,
+        }]);
+    };
+    let extracted = work_area.stage_dir("archive");
+    if extracted.exists() {
+        fs::remove_dir_all(&extracted).await?;
+    }
+    let mut documents = Vec::new();
+    for archive_path in archive::extract(origin, archive_kind, &extracted).await? {
+        let source = extracted.join(&archive_path);
+        match detect_input(&source).await {
+            Ok(InputKind::Archive(_)) => warn!(%archive_path, "Skipping a nested archive"),
+            Ok(kind) => documents.push(Document {
+                source,
+                kind,
+                archive_path: Some(archive_path),
+            }),
+            Err(err) => warn!(%archive_path, ?err, "Skipping an unsupported file"),
+        }
+    }
+    if documents.is_empty() {
+        return Err(DocumentError::NoDocuments.into());
+    }
+    info!(documents = documents.len(), "Extracted archive");
+    if payload.archive == ArchiveMode::Preserve {
+        return Ok(documents);
+    }
+
+    let merge_dir = work_area.stage_dir("merge");
+    if merge_dir.exists() {
+        fs::remove_dir_all(&merge_dir).await?;
+    }
+    fs::create_dir_all(&merge_dir).await?;
+    let mut pdfs = Vec::with_capacity(documents.len());
+    for (index, document) in documents.iter().enumerate() {
+        let pdf = convert_to_pdf(
+            &document.source,
+            document.kind,
+            &merge_dir.join(index.to_string()),
+            limits,
+        )
+        .await
+        .wrap_err_with(|| format!("failed to convert {} into a pdf", document.source))?;
+        pdfs.push(pdf);
+    }
+    let stem = Utf8Path::new(&payload.filename)
+        .file_stem()
+        .unwrap_or("merged");
+    let merged = merge_dir.join(format!("{stem}.pdf"));
+    merge_pdfs(&pdfs, &merged, limits).await?;
+    Ok(vec![Document {
+        source: merged,
+        kind: InputKind::Pdf,
+        archive_path: None,
+    }])
+}
+
+async fn download(payload: &Payload, working_dir: &Utf8Path) -> Result<Utf8PathBuf> {
+    fs::create_dir_all(working_dir).await?;
     let origin_file_path = working_dir.join(&payload.filename);
     let response = reqwest::get(payload.file_url.clone())
         .await
@@ -44,49 +292,506 @@ pub async fn process_input(payload: &Payload) -> Result<Vec<Utf8PathBuf>> {
     }
 
     info!(?origin_file_path, written_size, "Downloaded pdf");
-
-    let output_path = &working_dir.join("ocr");
-    fs::create_dir(output_path).await?;
-    process_file(output_path, &origin_file_path)
-        .await
-        .wrap_err("failed to process file")
+    Ok(origin_file_path)
 }
 
-#[instrument(ret)]
-async fn process_file(output_path: &Utf8Path, pdf_path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+#[instrument(ret, skip(limits, cancel))]
+async fn process_file(
+    output_path: &Utf8Path,
+    pdf_path: &Utf8Path,
+    requested_language: Option<&str>,
+    options: &OcrOptions,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+) -> Result<OcrOutput> {
     let original_filename = pdf_path.file_name().unwrap();
-    let ocred_pdf = output_path.join(original_filename);
-    let sidecar_file = Utf8PathBuf::from(original_filename).with_extension("txt");
-    let sidecar_file = output_path.join(sidecar_file);
-    let language = get_language_from_file(pdf_path).unwrap_or_else(|| "eng".to_string());
-    let arguments = [
-        "-l",
-        language.as_str(),
-        "--force-ocr",
-        "--rotate-pages",
-        "--deskew",
-        pdf_path.as_str(),
-        "--sidecar",
-        sidecar_file.as_str(),
-        ocred_pdf.as_str(),
-    ];
-    let mut command = Command::new("ocrmypdf");
-    command.args(arguments);
-    let output = command
-        .output()
-        .instrument(info_span!("ocrmypdf", ?arguments))
+    let ocred_pdf = output_path.join(Utf8PathBuf::from(original_filename).with_extension("pdf"));
+    let sidecar_file = options
+        .sidecar()
+        .then(|| output_path.join(Utf8PathBuf::from(original_filename).with_extension("txt")));
+    let language = resolve_language(
+        pdf_path,
+        requested_language,
+        options.language.as_deref(),
+        output_path,
+        limits,
+        cancel,
+    )
+    .await;
+    let engine_kind = options.engine.unwrap_or_default();
+    if engine_kind.uses_tesseract() {
+        let installed = bounded(
+            "listing the languages",
+            installed_languages(limits),
+            Deadline::Fixed(INSPECTION_TIMEOUT),
+            cancel,
+        )
+        .await?;
+        validate_languages(&language, &installed)?;
+    }
+    options.check_engine()?;
+    let engine = engine::engine(engine_kind)?;
+    // A pdf pdfinfo can not read may still be repaired by the engine, it gets the shortest time.
+    let (input_pages, ocr_timeout) = match count_pages(pdf_path, limits, cancel).await {
+        Ok(pages) => (pages, Deadline::Pages(pages)),
+        Err(err) => {
+            warn!(?err, "Failed to count the pages of the input");
+            // Without a page count running out of time does not tell the document is at fault.
+            (1, Deadline::Fixed(Deadline::Pages(1).duration()))
+        }
+    };
+    let output_type = options
+        .output_type
+        .unwrap_or_else(|| engine.default_output_type());
+    let mode = options.ocr_mode.unwrap_or_default();
+    let text_layer = match mode {
+        OcrMode::Auto | OcrMode::Skip => {
+            match bounded(
+                "the text extraction",
+                TextLayer::extract(pdf_path, limits),
+                ocr_timeout,
+                cancel,
+            )
+            .await
+            {
+                Ok(text_layer) => Some(text_layer),
+                Err(err) => {
+                    warn!(?err, "Failed to read the text layer of the input");
+                    None
+                }
+            }
+        }
+        OcrMode::Force | OcrMode::Redo => None,
+    };
+    let pages = text_layer
+        .as_ref()
+        .map(TextLayer::classify)
+        .unwrap_or_default();
+    let text_layer_pages = pages.iter().filter(|page| **page == PageText::Good).count();
+    // ocrmypdf refuses to redo the text layer of pages it has to deskew.
+    let redo = options.deskew != Some(true);
+    let mode = match text_layer::plan(mode, &pages, redo) {
+        // A pdf/a still has to be written by the engine, it skips the pages on its own.
+        OcrPlan::Skip if output_type != OutputType::Pdf => OcrMode::Skip,
+        OcrPlan::Skip => {
+            info!("Every page has text, keeping the pdf as it is");
+            fs::copy(pdf_path, &ocred_pdf)
+                .await
+                .wrap_err_with(|| format!("failed to copy {pdf_path}"))?;
+            if let (Some(sidecar_file), Some(text_layer)) = (&sidecar_file, &text_layer) {
+                fs::write(sidecar_file, text_layer.to_sidecar())
+                    .await
+                    .wrap_err("failed to write sidecar file")?;
+            }
+            let output = EngineOutput {
+                version: "none".to_string(),
+                ..Default::default()
+            };
+            let companions = bounded(
+                "the ocr result",
+                write_companions(
+                    &ocred_pdf,
+                    sidecar_file.as_deref(),
+                    &language,
+                    &output,
+                    Duration::ZERO,
+                    options,
+                    limits,
+                ),
+                ocr_timeout,
+                cancel,
+            )
+            .await?;
+            return Ok(OcrOutput {
+                page_count: count_output_pages(&ocred_pdf, input_pages, limits, cancel).await,
+                pdf: ocred_pdf,
+                sidecar: sidecar_file,
+                language,
+                engine: output.version,
+                conformance: None,
+                archive_path: None,
+                skip_reason: None,
+                text_layer_pages,
+                layout: companions.layout,
+                result: companions.result,
+                review: companions.review,
+                quality: companions.quality,
+            });
+        }
+        // Engines without preprocessing can not keep the text of some pages, they recognize all.
+        OcrPlan::Ocr(_) if !engine_kind.preprocesses() => OcrMode::Force,
+        OcrPlan::Ocr(mode) => mode,
+    };
+    info!(?mode, text_layer_pages, "Planned the ocr");
+    let options = &OcrOptions {
+        ocr_mode: Some(mode),
+        ..options.clone()
+    };
+    let recognition = recognition_formats(options);
+    let request = OcrRequest {
+        input: pdf_path,
+        pdf: &ocred_pdf,
+        sidecar: sidecar_file.as_deref(),
+        language: &language,
+        options,
+        limits,
+        recognition: &recognition,
+        work_dir: output_path,
+    };
+    let started = Instant::now();
+    let output = match bounded("the ocr", engine.ocr(&request), ocr_timeout, cancel).await {
+        Ok(output) => output,
+        Err(err) => match engine::recovery(&err) {
+            Recovery::SkipText if mode != OcrMode::Skip => {
+                warn!(?err, "Retrying the ocr skipping the pages with text");
+                let options = OcrOptions {
+                    ocr_mode: Some(OcrMode::Skip),
+                    ..options.clone()
+                };
+                let request = OcrRequest {
+                    options: &options,
+                    ..request
+                };
+                bounded("the ocr", engine.ocr(&request), ocr_timeout, cancel).await?
+            }
+            Recovery::UploadAsIs => {
+                warn!(?err, "Uploading the pdf without ocr");
+                fs::copy(pdf_path, &ocred_pdf)
+                    .await
+                    .wrap_err_with(|| format!("failed to copy {pdf_path}"))?;
+                return Ok(OcrOutput {
+                    page_count: count_output_pages(&ocred_pdf, input_pages, limits, cancel).await,
+                    pdf: ocred_pdf,
+                    sidecar: None,
+                    language,
+                    engine: "none".to_string(),
+                    conformance: None,
+                    archive_path: None,
+                    skip_reason: err.downcast_ref::<ExitCode>().copied(),
+                    text_layer_pages: 0,
+                    layout: Vec::new(),
+                    result: None,
+                    quality: None,
+                    review: false,
+                });
+            }
+            _ => return Err(err),
+        },
+    };
+    let engine_time = started.elapsed();
+    // ocrmypdf only leaves a note in the sidecar for the pages it skipped.
+    if let (OcrMode::Skip, Some(sidecar_file), Some(text_layer)) =
+        (mode, &sidecar_file, &text_layer)
+    {
+        let sidecar = fs::read_to_string(sidecar_file)
+            .await
+            .wrap_err("failed to read sidecar file")?;
+        fs::write(sidecar_file, text_layer.fill_sidecar(&sidecar))
+            .await
+            .wrap_err("failed to write sidecar file")?;
+    }
+    let companions = bounded(
+        "the ocr result",
+        write_companions(
+            &ocred_pdf,
+            sidecar_file.as_deref(),
+            &language,
+            &output,
+            engine_time,
+            options,
+            limits,
+        ),
+        ocr_timeout,
+        cancel,
+    )
+    .await?;
+    let conformance = bounded(
+        "the pdf/a validation",
+        check_conformance(
+            &ocred_pdf,
+            output_type,
+            options.pdfa_fallback.unwrap_or_default(),
+            output.pdfa_conversion_failed,
+            limits,
+        ),
+        ocr_timeout,
+        cancel,
+    )
+    .await?;
+    Ok(OcrOutput {
+        page_count: count_output_pages(&ocred_pdf, input_pages, limits, cancel).await,
+        pdf: ocred_pdf,
+        sidecar: sidecar_file,
+        language,
+        engine: output.version,
+        conformance,
+        archive_path: None,
+        skip_reason: None,
+        text_layer_pages: if mode == OcrMode::Skip {
+            text_layer_pages
+        } else {
+            0
+        },
+        layout: companions.layout,
+        result: companions.result,
+        review: companions.review,
+        quality: companions.quality,
+    })
+}
+
+/// Formats of the recognition the engine is asked to keep: the layout files of the profile and
+/// the hOCR the json result and the quality are read from.
+fn recognition_formats(options: &OcrOptions) -> Vec<LayoutFormat> {
+    let mut formats = options.layout.clone().unwrap_or_default();
+    if (options.json_result() || options.review_threshold.is_some())
+        && !formats.contains(&LayoutFormat::Hocr)
+    {
+        formats.push(LayoutFormat::Hocr);
+    }
+    formats
+}
+
+/// Write the layout files and the json result of the ocred `pdf` the profile asks for. Both come
+/// from the recognition of the engine, only layout formats the engine did not write are
+/// recognized again from the pdf. The quality is only rated on the pages the engine recognized.
+async fn write_companions(
+    pdf: &Utf8Path,
+    sidecar: Option<&Utf8Path>,
+    language: &str,
+    output: &EngineOutput,
+    engine_time: Duration,
+    options: &OcrOptions,
+    limits: &ResourceLimits,
+) -> Result<Companions> {
+    let started = Instant::now();
+    let formats = options.layout.as_deref().unwrap_or_default();
+    let mut layout = Vec::new();
+    let mut missing = Vec::new();
+    for format in formats {
+        match output.recognition(*format) {
+            Some(path) => layout.push(LayoutFile {
+                format: *format,
+                path: path.to_owned(),
+            }),
+            None => missing.push(*format),
+        }
+    }
+    if !missing.is_empty() {
+        info!(
+            ?missing,
+            "The engine did not write these layouts, recognizing the pages again"
+        );
+        layout.extend(layout::render(pdf, language, &missing, limits).await?);
+    }
+    if !options.json_result() && options.review_threshold.is_none() {
+        return Ok(Companions {
+            layout,
+            result: None,
+            quality: None,
+            review: false,
+        });
+    }
+    let hocr = output.recognition(LayoutFormat::Hocr);
+    let timings = Timings::new(engine_time, started.elapsed());
+    let result = ocr_result::build(
+        hocr,
+        sidecar,
+        &output.corrections,
+        language,
+        &output.version,
+        timings,
+        limits,
+    )
+    .await
+    .wrap_err("failed to build the ocr result")?;
+    // The hOCR was only kept for the result.
+    if let Some(hocr) = hocr.filter(|_| !formats.contains(&LayoutFormat::Hocr)) {
+        fs::remove_file(hocr).await?;
+    }
+    let quality = result.quality();
+    // A document keeping all of its text layer was not recognized, there is nothing to rate.
+    let review = result.is_recognized() && options.needs_review(quality);
+    info!(?quality, review, "Rated the ocr");
+    let result = if options.json_result() {
+        Some(result.write(pdf).await?)
+    } else {
+        None
+    };
+    Ok(Companions {
+        layout,
+        result,
+        quality,
+        review,
+    })
+}
+
+/// Ocr a document that came out below the review threshold again with stronger preprocessing and
+/// keep whichever try scored better. A failed retry keeps the first try unless the job was
+/// cancelled.
+#[instrument(skip(output, options, limits, cancel), fields(quality = output.quality))]
+async fn retry_low_quality(
+    output: OcrOutput,
+    retry_path: &Utf8Path,
+    pdf_path: &Utf8Path,
+    options: &OcrOptions,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+) -> Result<OcrOutput> {
+    let language = options
+        .retry_language
+        .as_deref()
+        .unwrap_or(&output.language);
+    info!(language, "Retrying the ocr of a low quality document");
+    let stronger = options.stronger();
+    let retried = process_file(
+        retry_path,
+        pdf_path,
+        Some(language),
+        &stronger,
+        limits,
+        cancel,
+    )
+    .await;
+    match retried {
+        Ok(retried) if retried.quality > output.quality => {
+            info!(quality = retried.quality, "The retry improved the quality");
+            Ok(retried)
+        }
+        Ok(retried) => {
+            info!(
+                quality = retried.quality,
+                "The retry did not improve the quality"
+            );
+            Ok(output)
+        }
+        Err(err) if cancel.is_cancelled() => Err(err),
+        Err(err) => {
+            warn!(?err, "Failed to retry the ocr, keeping the first try");
+            Ok(output)
+        }
+    }
+}
+
+/// Validate the ocred pdf and apply the profile's fallback policy when it does not conform.
+async fn check_conformance(
+    pdf: &Utf8Path,
+    output_type: OutputType,
+    fallback: PdfaFallback,
+    conversion_failed: bool,
+    limits: &ResourceLimits,
+) -> Result<Option<Conformance>> {
+    let conformance = pdfa::validate(pdf, output_type, limits)
         .await
-        .wrap_err("failed call spawn ocrmypdf")?;
+        .wrap_err("failed to validate the pdf/a conformance")?
+        .map(|conformance| Conformance {
+            valid: if conversion_failed {
+                Some(false)
+            } else {
+                conformance.valid
+            },
+            ..conformance
+        });
+    let Some(invalid) = conformance
+        .as_ref()
+        .filter(|conformance| conformance.is_invalid())
+    else {
+        return Ok(conformance);
+    };
+    match fallback {
+        PdfaFallback::Fail => Err(Report::new(DocumentError::Conformance(
+            invalid.requested.as_str().to_string(),
+        ))
+        .with_section(|| format!("{invalid:?}").header("Conformance:"))),
+        PdfaFallback::Pdf => {
+            warn!(conformance = ?invalid, "Keeping a pdf that does not conform");
+            Ok(conformance)
+        }
+    }
+}
+
+/// The pages of the ocred pdf are only reported, the ones of the input stand in when pdfinfo can
+/// not read it.
+async fn count_output_pages(
+    pdf: &Utf8Path,
+    input_pages: usize,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+) -> usize {
+    match count_pages(pdf, limits, cancel).await {
+        Ok(pages) => pages,
+        Err(err) => {
+            warn!(?err, "Failed to count the pages of the ocred pdf");
+            input_pages
+        }
+    }
+}
+
+/// The sidecar is optional so the pages are counted on the pdf itself.
+async fn count_pages(
+    pdf: &Utf8Path,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    let output = bounded(
+        "pdfinfo",
+        async {
+            limits
+                .output(Command::new("pdfinfo").arg(pdf.as_str()))
+                .await
+                .wrap_err("failed call spawn pdfinfo")
+        },
+        Deadline::Fixed(INSPECTION_TIMEOUT),
+        cancel,
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(eyre!("failed to read the pdf info of {pdf}"));
+    }
+    parse_page_count(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| eyre!("pdfinfo did not report the pages of {pdf}"))
+}
+
+fn parse_page_count(pdfinfo: &str) -> Option<usize> {
+    pdfinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pages:"))
+        .and_then(|pages| pages.trim().parse().ok())
+}
 
-    if output.status.success() {
-        return Ok(vec![ocred_pdf, sidecar_file]);
+/// The language requested on the payload wins over the filename suffix which wins over the key's
+/// default, only when none is set the language is detected from the document itself.
+async fn resolve_language(
+    pdf_path: &Utf8Path,
+    requested_language: Option<&str>,
+    default_language: Option<&str>,
+    work_dir: &Utf8Path,
+    limits: &ResourceLimits,
+    cancel: &CancellationToken,
+) -> String {
+    if let Some(language) = requested_language {
+        return language.to_string();
+    }
+    if let Some(language) = get_language_from_file(pdf_path) {
+        return language;
+    }
+    if let Some(language) = default_language {
+        return language.to_string();
+    }
+    // Only the first page is recognized.
+    let detection = bounded(
+        "the language detection",
+        detect_language(pdf_path, work_dir, limits),
+        Deadline::Pages(1),
+        cancel,
+    );
+    match detection.await {
+        Ok(Some(language)) => language,
+        Ok(None) => DEFAULT_LANGUAGE.to_string(),
+        Err(err) => {
+            warn!(?err, "Failed to detect the language");
+            DEFAULT_LANGUAGE.to_string()
+        }
     }
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    Err(eyre!("ocrymypdf failed")
-        .with_section(|| output.status.to_string().header("Status code:"))
-        .with_section(|| stderr.trim().to_string().header("Stderr:"))
-        .with_section(|| stdout.trim().to_string().header("Stdout:")))
 }
 
 fn get_language_from_file(path: &Utf8Path) -> Option<String> {
@@ -98,39 +803,131 @@ fn get_language_from_file(path: &Utf8Path) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
-    use camino::Utf8PathBuf;
+    use camino::{Utf8Path, Utf8PathBuf};
     use color_eyre::{eyre::WrapErr, Result};
     use tempfile::tempdir;
     use test_case::test_case;
     use tokio::fs;
 
-    use crate::ocr::{get_language_from_file, process_file};
+    use crate::{
+        engine::EngineKind,
+        limits::ResourceLimits,
+        ocr::{get_language_from_file, parse_page_count, process_file},
+        profile::{OcrMode, OcrOptions, OutputType},
+    };
+    use tokio_util::sync::CancellationToken;
 
     #[test_case("german.deu.pdf" => Some("deu".to_string()))]
     #[test_case("english.eng.pdf" => Some("eng".to_string()))]
     #[test_case("non_matching.pdf" => None)]
     #[test_case("portuguese.por.pdf" => Some("por".to_string()))]
+    #[test_case("mixed.deu+eng.pdf" => Some("deu+eng".to_string()))]
+    #[test_case("chinese.chi_sim.pdf" => Some("chi_sim".to_string()))]
+    #[test_case("vertical.chi_tra_vert+eng.pdf" => Some("chi_tra_vert+eng".to_string()))]
+    #[test_case("broken.deu+.pdf" => None)]
     pub fn get_language(language: &str) -> Option<String> {
         let file = Utf8PathBuf::from(language);
         get_language_from_file(&file)
     }
 
+    #[test_case("Producer:        pikepdf 8.7.1\nPages:           3\nEncrypted:       no\n" => Some(3))]
+    #[test_case("Producer:        pikepdf 8.7.1\n" => None)]
+    fn page_count(pdfinfo: &str) -> Option<usize> {
+        parse_page_count(pdfinfo)
+    }
+
+    /// Runs without ocrmypdf, tesseract or poppler.
+    #[tokio::test]
+    async fn process_with_the_fake_engine() -> Result<()> {
+        let working_dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let options = OcrOptions {
+            engine: Some(EngineKind::Fake),
+            ocr_mode: Some(OcrMode::Force),
+            ..Default::default()
+        };
+
+        let output = process_file(
+            &working_dir,
+            Utf8Path::new("fixtures/test.pdf"),
+            Some("deu"),
+            &options,
+            &ResourceLimits::default(),
+            &CancellationToken::new(),
+        )
+        .await?;
+        assert_eq!(output.engine, "fake");
+        assert_eq!(output.language, "deu");
+        assert_eq!(output.page_count, 1);
+        assert_eq!(output.conformance, None);
+        assert_eq!(
+            fs::read("fixtures/test.pdf").await?,
+            fs::read(&output.pdf).await?
+        );
+        assert_eq!(
+            fs::read_to_string(output.sidecar.unwrap()).await?,
+            "Fake ocr text (deu)\n\x0c"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_pdfa_from_the_fake_engine() -> Result<()> {
+        let working_dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
+        let options = OcrOptions {
+            engine: Some(EngineKind::Fake),
+            output_type: Some(OutputType::Pdfa),
+            ..Default::default()
+        };
+        let result = process_file(
+            &working_dir,
+            Utf8Path::new("fixtures/test.pdf"),
+            Some("deu"),
+            &options,
+            &ResourceLimits::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
     #[test_case("fixtures/test.pdf")]
     #[test_case("fixtures/test-rotated.pdf")]
     #[tokio::test]
     async fn test_ocr_pdf(fixture: &str) -> Result<()> {
+        assert_ocr(fixture, &OcrOptions::default()).await
+    }
+
+    /// Only built by the ci step testing the tesseract feature.
+    #[cfg(feature = "tesseract")]
+    #[tokio::test]
+    async fn test_ocr_pdf_with_tesseract() -> Result<()> {
+        let options = OcrOptions {
+            engine: Some(EngineKind::Tesseract),
+            ..Default::default()
+        };
+        assert_ocr("fixtures/test.pdf", &options).await
+    }
+
+    async fn assert_ocr(fixture: &str, options: &OcrOptions) -> Result<()> {
         let working_dir = Utf8PathBuf::from_path_buf(tempdir()?.into_path()).unwrap();
         let test_pdf = Utf8PathBuf::from(fixture);
 
-        let mut files = process_file(&working_dir, test_pdf.as_path())
-            .await?
-            .into_iter();
-        let pdf = files.next().unwrap();
-        let txt = files.next().unwrap();
-        assert!(pdf.exists());
-        assert!(txt.exists());
+        let output = process_file(
+            &working_dir,
+            test_pdf.as_path(),
+            None,
+            options,
+            &ResourceLimits::default(),
+            &CancellationToken::new(),
+        )
+        .await?;
+        assert_eq!(output.language, "eng");
+        assert!(output.pdf.exists());
+        let sidecar = output.sidecar.unwrap();
+        assert!(sidecar.exists());
 
-        let ocred = fs::read_to_string(txt).await?;
+        let ocred = fs::read_to_string(sidecar).await?;
         let expected_file = format!("{fixture}.expected.txt");
         let expected = fs::read_to_string(&expected_file)
             .await
@@ -0,0 +1,473 @@
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, process::Command};
+use tracing::instrument;
+
+use crate::{engine::PageCorrection, limits::ResourceLimits};
+
+lazy_static! {
+    /// The hOCR elements tesseract writes for pages, lines and words, with their title.
+    static ref HOCR_ELEMENT_REGEX: Regex = Regex::new(
+        r#"<(?:div|p|span) class=['"](ocr_page|ocr_line|ocr_header|ocr_caption|ocr_textfloat|ocrx_word)['"][^>]*? title=(?:'([^']*)'|"([^"]*)")[^>]*>"#
+    )
+    .expect("invalid regex");
+    static ref HOCR_BBOX_REGEX: Regex =
+        Regex::new(r"bbox (\d+) (\d+) (\d+) (\d+)").expect("invalid regex");
+    static ref HOCR_CONFIDENCE_REGEX: Regex =
+        Regex::new(r"x_wconf (\d+(?:\.\d+)?)").expect("invalid regex");
+    static ref HOCR_PAGE_NUMBER_REGEX: Regex = Regex::new(r"ppageno (\d+)").expect("invalid regex");
+    static ref TAG_REGEX: Regex = Regex::new(r"<[^>]*>").expect("invalid regex");
+}
+
+/// Everything known about the ocr of a document, to audit its quality and build on it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OcrResult {
+    pub page_count: usize,
+    pub language: String,
+    pub versions: Versions,
+    pub timings: Timings,
+    pub pages: Vec<PageResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Versions {
+    /// The engine that produced the pdf e.g. `ocrmypdf 15.4.0`.
+    pub engine: String,
+    /// The tesseract that produced the boxes and confidences, `None` when no page was recognized.
+    pub tesseract: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    pub engine_ms: u64,
+    pub layout_ms: u64,
+}
+
+impl Timings {
+    pub fn new(engine: Duration, layout: Duration) -> Self {
+        Self {
+            engine_ms: engine.as_millis() as u64,
+            layout_ms: layout.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PageResult {
+    /// Page number starting at 1.
+    pub number: usize,
+    /// Recognized by the engine, pages that kept their text layer have no words.
+    pub recognized: bool,
+    /// Size of the page in pixels, the unit of the boxes.
+    pub width: u32,
+    pub height: u32,
+    pub rotation: Option<u16>,
+    pub deskew: Option<f32>,
+    pub text: String,
+    /// Mean confidence of the words of the page from 0 to 100, `None` without words.
+    pub confidence: Option<f32>,
+    pub lines: Vec<LineResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineResult {
+    pub bbox: BoundingBox,
+    pub confidence: Option<f32>,
+    pub words: Vec<WordResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WordResult {
+    pub text: String,
+    pub bbox: BoundingBox,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl LineResult {
+    fn text(&self) -> String {
+        self.words
+            .iter()
+            .map(|word| word.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl OcrResult {
+    /// Combine the `hocr` of the pages the engine recognized with what else it reported. The
+    /// `sidecar` wins for the text of the pages, it keeps the digital text of pages that were not
+    /// ocred.
+    pub fn new(
+        hocr: Option<&str>,
+        sidecar: Option<&str>,
+        corrections: &[PageCorrection],
+        language: String,
+        versions: Versions,
+        timings: Timings,
+    ) -> Result<Self> {
+        let mut recognized = hocr.map(parse_hocr).transpose()?.unwrap_or_default();
+        let mut sidecar_pages = sidecar
+            .map(|sidecar| sidecar.split('\x0c').collect::<Vec<_>>())
+            .unwrap_or_default();
+        // The sidecar ends every page with a form feed.
+        if sidecar_pages.last().is_some_and(|page| page.is_empty()) {
+            sidecar_pages.pop();
+        }
+        let page_count = recognized
+            .last()
+            .map_or(0, |page| page.number)
+            .max(sidecar_pages.len());
+        let mut pages = (1..=page_count)
+            .map(|number| match recognized.first() {
+                Some(page) if page.number == number => recognized.remove(0),
+                _ => PageResult {
+                    number,
+                    ..Default::default()
+                },
+            })
+            .collect::<Vec<_>>();
+        for page in &mut pages {
+            page.text = match sidecar_pages.get(page.number - 1) {
+                Some(text) => text.trim_end().to_string(),
+                None => page
+                    .lines
+                    .iter()
+                    .map(LineResult::text)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            };
+            if let Some(correction) = corrections
+                .iter()
+                .find(|correction| correction.page == page.number)
+            {
+                page.rotation = correction.rotation;
+                page.deskew = correction.deskew;
+            }
+        }
+        Ok(Self {
+            page_count: pages.len(),
+            language,
+            versions,
+            timings,
+            pages,
+        })
+    }
+
+    /// Whether the engine recognized any of the pages.
+    pub fn is_recognized(&self) -> bool {
+        self.pages.iter().any(|page| page.recognized)
+    }
+
+    /// Mean confidence of every word of the document from 0 to 100, `None` without words. Only
+    /// recognized pages have words, the ones that kept their text layer do not count.
+    pub fn quality(&self) -> Option<f32> {
+        mean(
+            self.pages
+                .iter()
+                .flat_map(|page| &page.lines)
+                .flat_map(|line| &line.words)
+                .map(|word| word.confidence),
+        )
+    }
+
+    /// Write the result next to the ocred `pdf`.
+    pub async fn write(&self, pdf: &Utf8Path) -> Result<Utf8PathBuf> {
+        let path = pdf.with_extension("json");
+        fs::write(&path, serde_json::to_vec(self)?)
+            .await
+            .wrap_err_with(|| format!("failed to write {path}"))?;
+        Ok(path)
+    }
+
+    pub async fn read(path: &Utf8Path) -> Result<Self> {
+        let content = fs::read(path)
+            .await
+            .wrap_err_with(|| format!("failed to read {path}"))?;
+        serde_json::from_slice(&content).wrap_err("invalid ocr result")
+    }
+}
+
+/// Build the result of the ocred `pdf` from the `hocr` of the pages the engine recognized.
+#[instrument(skip(corrections, timings, limits))]
+pub async fn build(
+    hocr: Option<&Utf8Path>,
+    sidecar: Option<&Utf8Path>,
+    corrections: &[PageCorrection],
+    language: &str,
+    engine: &str,
+    timings: Timings,
+    limits: &ResourceLimits,
+) -> Result<OcrResult> {
+    let (hocr, tesseract) = match hocr {
+        Some(hocr) => (
+            Some(
+                fs::read_to_string(hocr)
+                    .await
+                    .wrap_err_with(|| format!("failed to read {hocr}"))?,
+            ),
+            Some(tesseract_version(limits).await?),
+        ),
+        None => (None, None),
+    };
+    let sidecar = match sidecar {
+        Some(sidecar) => Some(
+            fs::read_to_string(sidecar)
+                .await
+                .wrap_err("failed to read sidecar file")?,
+        ),
+        None => None,
+    };
+    let versions = Versions {
+        engine: engine.to_string(),
+        tesseract,
+    };
+    OcrResult::new(
+        hocr.as_deref(),
+        sidecar.as_deref(),
+        corrections,
+        language.to_string(),
+        versions,
+        timings,
+    )
+}
+
+/// Pages with their lines and words from tesseract's hOCR, the text is left to the caller.
+fn parse_hocr(hocr: &str) -> Result<Vec<PageResult>> {
+    let mut pages = Vec::<PageResult>::new();
+    for captures in HOCR_ELEMENT_REGEX.captures_iter(hocr) {
+        let title = captures
+            .get(2)
+            .or_else(|| captures.get(3))
+            .map_or("", |title| title.as_str());
+        let bbox =
+            parse_bbox(title).ok_or_else(|| eyre!("hocr element without a bbox {title:?}"))?;
+        match &captures[1] {
+            "ocr_page" => {
+                let number = HOCR_PAGE_NUMBER_REGEX
+                    .captures(title)
+                    .and_then(|number| number[1].parse::<usize>().ok())
+                    .map_or(pages.len() + 1, |number| number + 1);
+                pages.push(PageResult {
+                    number,
+                    recognized: true,
+                    width: bbox.width,
+                    height: bbox.height,
+                    ..Default::default()
+                });
+            }
+            "ocrx_word" => {
+                let end = captures.get(0).map_or(0, |element| element.end());
+                let content = hocr[end..].split("</span>").next().unwrap_or_default();
+                let text = decode_entities(&TAG_REGEX.replace_all(content, ""));
+                let confidence = HOCR_CONFIDENCE_REGEX
+                    .captures(title)
+                    .and_then(|confidence| confidence[1].parse().ok())
+                    .ok_or_else(|| eyre!("hocr word without a confidence {title:?}"))?;
+                let line = pages
+                    .last_mut()
+                    .and_then(|page| page.lines.last_mut())
+                    .ok_or_else(|| eyre!("hocr word outside of a line"))?;
+                if !text.trim().is_empty() {
+                    line.words.push(WordResult {
+                        text,
+                        bbox,
+                        confidence,
+                    });
+                }
+            }
+            _ => {
+                let page = pages
+                    .last_mut()
+                    .ok_or_else(|| eyre!("hocr line outside of a page"))?;
+                page.lines.push(LineResult {
+                    bbox,
+                    confidence: None,
+                    words: Vec::new(),
+                });
+            }
+        }
+    }
+    for page in &mut pages {
+        page.lines.retain(|line| !line.words.is_empty());
+        for line in &mut page.lines {
+            line.confidence = mean(line.words.iter().map(|word| word.confidence));
+        }
+        page.confidence = mean(
+            page.lines
+                .iter()
+                .flat_map(|line| &line.words)
+                .map(|word| word.confidence),
+        );
+    }
+    pages.sort_by_key(|page| page.number);
+    Ok(pages)
+}
+
+/// hOCR boxes are corners, `bbox left top right bottom`.
+fn parse_bbox(title: &str) -> Option<BoundingBox> {
+    let captures = HOCR_BBOX_REGEX.captures(title)?;
+    let number = |index: usize| captures[index].parse::<u32>().ok();
+    let (left, top, right, bottom) = (number(1)?, number(2)?, number(3)?, number(4)?);
+    Some(BoundingBox {
+        left,
+        top,
+        width: right.saturating_sub(left),
+        height: bottom.saturating_sub(top),
+    })
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+#[instrument(skip(limits), ret)]
+pub async fn tesseract_version(limits: &ResourceLimits) -> Result<String> {
+    let output = limits
+        .output(Command::new("tesseract").arg("--version"))
+        .await
+        .wrap_err("failed call spawn tesseract")?;
+    if !output.status.success() {
+        return Err(eyre!("failed to get the tesseract version"));
+    }
+    // Older versions print it on stderr.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .find(|line| line.starts_with("tesseract "))
+        .map(|line| line.trim().to_string())
+        .ok_or_else(|| eyre!("tesseract did not report its version"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hocr, OcrResult, Timings, Versions};
+    use crate::engine::PageCorrection;
+
+    const HOCR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en" lang="en">
+ <body>
+  <div class='ocr_page' id='page_1' title='image "/tmp/000001.png"; bbox 0 0 2480 3508; ppageno 0; scan_res 300 300'>
+   <div class='ocr_carea' id='block_1_1' title="bbox 100 200 900 260">
+    <p class='ocr_par' id='par_1_1' lang='deu' title="bbox 100 200 900 260">
+     <span class='ocr_line' id='line_1_1' title="bbox 100 200 900 260; baseline 0 -10; x_size 60">
+      <span class='ocrx_word' id='word_1_1' title='bbox 100 200 400 260; x_wconf 96'>Rechnung</span>
+      <span class='ocrx_word' id='word_1_2' title='bbox 450 200 900 260; x_wconf 92'><strong>2024&amp;17</strong></span>
+     </span>
+    </p>
+   </div>
+  </div>
+  <div class='ocr_page' id='page_3' title='image "/tmp/000003.png"; bbox 0 0 2480 3508; ppageno 2; scan_res 300 300'>
+  </div>
+ </body>
+</html>
+"#;
+
+    fn versions() -> Versions {
+        Versions {
+            engine: "ocrmypdf 15.4.0".to_string(),
+            tesseract: Some("tesseract 5.3.0".to_string()),
+        }
+    }
+
+    fn timings() -> Timings {
+        Timings {
+            engine_ms: 1,
+            layout_ms: 2,
+        }
+    }
+
+    #[test]
+    fn parse_pages_lines_and_words() {
+        let pages = parse_hocr(HOCR).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!((pages[0].width, pages[0].height), (2480, 3508));
+        assert_eq!(pages[0].lines.len(), 1);
+        assert_eq!(pages[0].lines[0].words[1].text, "2024&17");
+        assert_eq!(pages[0].lines[0].words[1].bbox.width, 450);
+        assert_eq!(pages[0].lines[0].confidence, Some(94.0));
+        assert_eq!(pages[0].confidence, Some(94.0));
+        assert_eq!(pages[1].number, 3);
+        assert_eq!(pages[1].confidence, None);
+    }
+
+    #[test]
+    fn sidecar_text_and_corrections() {
+        let result = OcrResult::new(
+            Some(HOCR),
+            Some("Rechnung 2024&17\n\x0cdigital text\n\x0c\x0c"),
+            &[PageCorrection {
+                page: 3,
+                rotation: Some(90),
+                deskew: Some(0.5),
+            }],
+            "deu".to_string(),
+            versions(),
+            timings(),
+        )
+        .unwrap();
+        assert_eq!(result.page_count, 3);
+        assert_eq!(result.pages[1].text, "digital text");
+        assert!(!result.pages[1].recognized);
+        assert_eq!(result.pages[2].rotation, Some(90));
+        assert_eq!(result.pages[0].rotation, None);
+        assert_eq!(result.quality(), Some(94.0));
+    }
+
+    #[test]
+    fn text_from_the_words_without_sidecar() {
+        let result = OcrResult::new(
+            Some(HOCR),
+            None,
+            &[],
+            "deu".to_string(),
+            versions(),
+            timings(),
+        )
+        .unwrap();
+        assert_eq!(result.pages[0].text, "Rechnung 2024&17");
+    }
+
+    #[test]
+    fn text_layer_pages_are_not_rated() {
+        let result = OcrResult::new(
+            None,
+            Some("digital text\n\x0c"),
+            &[],
+            "deu".to_string(),
+            versions(),
+            timings(),
+        )
+        .unwrap();
+        assert_eq!(result.page_count, 1);
+        assert!(!result.is_recognized());
+        assert_eq!(result.quality(), None);
+    }
+}
@@ -0,0 +1,853 @@
+use std::collections::HashMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{Datelike, Utc};
+use clap::{Args, ValueEnum};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    errors::DocumentError,
+    ocr::OcrOutput,
+    upload::{file_id_from_url, Destination, FileRole, Format, Metadata, Upload, JOB_ID_PROPERTY},
+    Payload,
+};
+
+pub const DEFAULT_FOLDER_TEMPLATE: &str = "{parent}/Done";
+pub const DEFAULT_REVIEW_FOLDER_TEMPLATE: &str = "{parent}/Review";
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{original_stem}.pdf";
+pub const DEFAULT_SIDECAR_FILENAME_TEMPLATE: &str = "{original_stem}.txt";
+pub const DEFAULT_DOCUMENT_FILENAME_TEMPLATE: &str = "{original_stem}";
+
+/// Variables of [TemplateContext] with the values templates are checked with before they are saved.
+const SAMPLE_VARIABLES: [(&str, &str); 9] = [
+    ("year", "2024"),
+    ("month", "01"),
+    ("day", "31"),
+    ("parent", "/Scans"),
+    ("original_stem", "scan"),
+    ("original_name", "scan.pdf"),
+    ("language", "eng"),
+    ("job_id", "00000000-0000-0000-0000-000000000000"),
+    ("page_count", "1"),
+];
+
+lazy_static! {
+    static ref TEMPLATE_VARIABLE_REGEX: Regex =
+        Regex::new(r"\{([a-z_]+)\}").expect("invalid regex");
+}
+
+/// Where and under which names the results of an ocr job are uploaded.
+/// Every field is optional so per request options can be layered on top of the per key ones.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Args)]
+pub struct OutputOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long = "folder-template",
+        help = "Folder where the ocred pdf is uploaded to e.g. Archive/{year}/{month}"
+    )]
+    pub folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long = "review-folder-template",
+        help = "Folder where documents below the review threshold are uploaded to instead"
+    )]
+    pub review_folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long = "filename-template",
+        help = "Name of the uploaded ocred pdf e.g. {original_stem}-ocr.pdf"
+    )]
+    pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long = "sidecar-folder-template",
+        help = "Folder where the text sidecar is uploaded to, defaults to the pdf's folder"
+    )]
+    pub sidecar_folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long = "sidecar-filename-template",
+        help = "Name of the uploaded text sidecar"
+    )]
+    pub sidecar_filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_enum,
+        help = "Create new files or replace the original document"
+    )]
+    pub mode: Option<UploadMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Pin the original revision when replacing it so drive does not purge it"
+    )]
+    pub keep_original_revision: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_enum, help = "How the ocred text is delivered")]
+    pub sidecar: Option<SidecarOutput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do when a file with the same name already exists"
+    )]
+    pub on_collision: Option<CollisionPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Name of the shared drive the paths are in, defaults to My Drive"
+    )]
+    pub shared_drive: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Upload the json result next to the pdf, it is always kept with the job status"
+    )]
+    pub upload_json_result: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadMode {
+    /// Upload the results as new files into the output folder.
+    #[default]
+    Create,
+    /// Upload the ocred pdf as a new revision of the original drive file, the sidecar text is
+    /// attached as the file's indexable text instead of being uploaded. Documents below the
+    /// review threshold are uploaded into the review folder and leave the original alone.
+    ReplaceOriginal,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SidecarOutput {
+    /// Upload the text as a separate `.txt` file.
+    #[default]
+    File,
+    /// Write the text into the pdf's drive search index together with the job's metadata.
+    Index,
+    /// Convert the text into a google doc that links back to the ocred pdf.
+    GoogleDoc,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Create another file with the same name, drive allows it.
+    #[default]
+    Duplicate,
+    /// Upload as a new revision of the existing file.
+    Overwrite,
+    /// Keep the existing file and do not upload.
+    Skip,
+    /// Append a number to the name e.g. `scan (1).pdf`.
+    Suffix,
+    /// Append the upload time to the name e.g. `scan 20240101T120000.pdf`.
+    Timestamp,
+}
+
+impl OutputOptions {
+    /// Fill every option that is not set with the one from `defaults`.
+    pub fn or(self, defaults: OutputOptions) -> Self {
+        Self {
+            folder: self.folder.or(defaults.folder),
+            review_folder: self.review_folder.or(defaults.review_folder),
+            filename: self.filename.or(defaults.filename),
+            sidecar_folder: self.sidecar_folder.or(defaults.sidecar_folder),
+            sidecar_filename: self.sidecar_filename.or(defaults.sidecar_filename),
+            mode: self.mode.or(defaults.mode),
+            keep_original_revision: self
+                .keep_original_revision
+                .or(defaults.keep_original_revision),
+            sidecar: self.sidecar.or(defaults.sidecar),
+            on_collision: self.on_collision.or(defaults.on_collision),
+            shared_drive: self.shared_drive.or(defaults.shared_drive),
+            upload_json_result: self.upload_json_result.or(defaults.upload_json_result),
+        }
+    }
+
+    /// Render the templates with sample values so a typo fails when they are saved and not in
+    /// every job.
+    pub fn validate(&self) -> Result<()> {
+        let context = TemplateContext::sample();
+        for template in [&self.folder, &self.review_folder, &self.sidecar_folder]
+            .into_iter()
+            .flatten()
+        {
+            context.render(template)?;
+        }
+        for template in [&self.filename, &self.sidecar_filename]
+            .into_iter()
+            .flatten()
+        {
+            context.render_filename(template)?;
+        }
+        Ok(())
+    }
+}
+
+/// Variables available to the output templates, referenced as `{name}`.
+#[derive(Debug)]
+pub struct TemplateContext {
+    variables: HashMap<&'static str, String>,
+}
+
+impl TemplateContext {
+    pub fn new(payload: &Payload, job_id: Uuid, output: &OcrOutput) -> Self {
+        let now = Utc::now();
+        // Documents of an archive are named after their own file.
+        let original_name = match output
+            .archive_path
+            .as_ref()
+            .and_then(|path| path.file_name())
+        {
+            Some(name) => name.to_string(),
+            None => payload.filename.clone(),
+        };
+        let parent = payload
+            .path
+            .parent()
+            .unwrap_or(payload.path.as_path())
+            .to_string();
+        let variables = HashMap::from([
+            ("year", format!("{:04}", now.year())),
+            ("month", format!("{:02}", now.month())),
+            ("day", format!("{:02}", now.day())),
+            ("parent", parent),
+            (
+                "original_stem",
+                Utf8Path::new(&original_name)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            ("original_name", original_name),
+            ("language", output.language.clone()),
+            ("job_id", job_id.to_string()),
+            ("page_count", output.page_count.to_string()),
+        ]);
+        Self { variables }
+    }
+
+    fn sample() -> Self {
+        let variables = SAMPLE_VARIABLES
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect();
+        Self { variables }
+    }
+
+    /// Render the name of a file, which can not contain folders.
+    pub fn render_filename(&self, template: &str) -> Result<String> {
+        let rendered = self.render(template)?;
+        if rendered.contains('/') {
+            return Err(DocumentError::FilenameTemplate(template.to_string()).into());
+        }
+        Ok(rendered)
+    }
+
+    pub fn render(&self, template: &str) -> Result<String> {
+        let mut unknown = vec![];
+        let rendered = TEMPLATE_VARIABLE_REGEX.replace_all(template, |captures: &Captures| {
+            let name = &captures[1];
+            match self.variables.get(name) {
+                Some(value) => value.clone(),
+                None => {
+                    unknown.push(name.to_string());
+                    String::new()
+                }
+            }
+        });
+        if !unknown.is_empty() {
+            return Err(DocumentError::UnknownVariables {
+                template: template.to_string(),
+                variables: unknown,
+            }
+            .into());
+        }
+        Ok(rendered.into_owned())
+    }
+}
+
+/// Drive metadata used to trace an ocred pdf back to the job and document it came from.
+pub fn pdf_metadata(payload: &Payload, job_id: Uuid, output: &OcrOutput) -> Metadata {
+    let source_url_hash = hex::encode(Sha256::digest(payload.file_url.as_str()));
+    let mut app_properties = HashMap::from([
+        (JOB_ID_PROPERTY.to_string(), job_id.to_string()),
+        ("drive_ocr_language".to_string(), output.language.clone()),
+        (
+            "drive_ocr_page_count".to_string(),
+            output.page_count.to_string(),
+        ),
+        ("drive_ocr_engine".to_string(), output.engine.clone()),
+        ("drive_ocr_source_url_sha256".to_string(), source_url_hash),
+    ]);
+    if let Some(conformance) = &output.conformance {
+        app_properties.extend([
+            (
+                "drive_ocr_pdfa_requested".to_string(),
+                conformance.requested.as_str().to_string(),
+            ),
+            (
+                "drive_ocr_pdfa_declared".to_string(),
+                conformance.declared.clone().unwrap_or_default(),
+            ),
+            (
+                "drive_ocr_pdfa_valid".to_string(),
+                conformance.status().to_string(),
+            ),
+            (
+                "drive_ocr_pdfa_validator".to_string(),
+                conformance.validator.clone(),
+            ),
+        ]);
+    }
+    if let Some(skip_reason) = output.skip_reason {
+        app_properties.insert("drive_ocr_skip_reason".to_string(), skip_reason.to_string());
+    }
+    if let Some(quality) = output.quality {
+        app_properties.insert("drive_ocr_quality".to_string(), format!("{quality:.1}"));
+    }
+    if output.review {
+        app_properties.insert("drive_ocr_review".to_string(), true.to_string());
+    }
+    let description = format!(
+        "OCRed from {} ({} pages, language {}, {}, job {job_id})",
+        payload.filename, output.page_count, output.language, output.engine
+    );
+    Metadata {
+        indexable_text: output.sidecar.clone(),
+        app_properties: Some(app_properties),
+        description: Some(description),
+    }
+}
+
+/// Render the output templates into the list of files that have to be uploaded.
+pub fn plan_uploads(
+    payload: &Payload,
+    options: &OutputOptions,
+    job_id: Uuid,
+    outputs: &[OcrOutput],
+) -> Result<Vec<Upload>> {
+    if outputs.len() > 1 && options.mode.unwrap_or_default() == UploadMode::ReplaceOriginal {
+        return Err(DocumentError::InvalidOptions(format!(
+            "an archive of {} documents can not replace the original, merge them instead",
+            outputs.len()
+        ))
+        .into());
+    }
+    let mut uploads = Vec::new();
+    for output in outputs {
+        let offset = uploads.len();
+        uploads.extend(
+            plan_document_uploads(payload, options, job_id, output)?
+                .into_iter()
+                .map(|upload| upload.offset_links(offset)),
+        );
+    }
+    Ok(uploads)
+}
+
+fn plan_document_uploads(
+    payload: &Payload,
+    options: &OutputOptions,
+    job_id: Uuid,
+    output: &OcrOutput,
+) -> Result<Vec<Upload>> {
+    // A document that needs a look does not replace the original, it goes to the review folder.
+    if options.mode.unwrap_or_default() == UploadMode::ReplaceOriginal && !output.review {
+        let file_id = payload
+            .file_id
+            .clone()
+            .or_else(|| file_id_from_url(&payload.file_url));
+        let layout = companion_uploads(
+            output,
+            payload.path.parent().unwrap_or(Utf8Path::new("")),
+            &payload.filename,
+            options.on_collision.unwrap_or_default(),
+            options.shared_drive.clone(),
+            options.upload_json_result.unwrap_or_default(),
+        );
+        let pdf = Upload {
+            source: output.pdf.clone(),
+            destination: Destination::Revision {
+                file_id,
+                path: payload.path.clone(),
+                keep_previous_revision: options.keep_original_revision.unwrap_or_default(),
+                shared_drive: options.shared_drive.clone(),
+            },
+            metadata: pdf_metadata(payload, job_id, output),
+            format: Format::AsIs,
+            role: FileRole::Pdf,
+            document: output.archive_path.clone(),
+        };
+        return Ok([pdf].into_iter().chain(layout).collect());
+    }
+
+    let context = TemplateContext::new(payload, job_id, output);
+    // Documents of an archive keep the folders they had inside of it.
+    let archive_folder = output
+        .archive_path
+        .as_ref()
+        .and_then(|path| path.parent())
+        .filter(|folder| !folder.as_str().is_empty());
+    let in_archive_folder = |folder: String| match archive_folder {
+        Some(archive_folder) => Utf8PathBuf::from(folder).join(archive_folder),
+        None => Utf8PathBuf::from(folder),
+    };
+    // Documents that need a look go to the review folder, their sidecar follows them unless it
+    // has a folder of its own.
+    let folder_template = if output.review {
+        options
+            .review_folder
+            .as_deref()
+            .unwrap_or(DEFAULT_REVIEW_FOLDER_TEMPLATE)
+    } else {
+        options.folder.as_deref().unwrap_or(DEFAULT_FOLDER_TEMPLATE)
+    };
+    let folder = in_archive_folder(context.render(folder_template)?);
+    let sidecar_folder = match options.sidecar_folder.as_deref() {
+        Some(template) => in_archive_folder(context.render(template)?),
+        None => folder.clone(),
+    };
+    let filename = context.render_filename(
+        options
+            .filename
+            .as_deref()
+            .unwrap_or(DEFAULT_FILENAME_TEMPLATE),
+    )?;
+    let on_collision = options.on_collision.unwrap_or_default();
+    let sidecar = options.sidecar.unwrap_or_default();
+    let default_sidecar_filename = match sidecar {
+        SidecarOutput::GoogleDoc => DEFAULT_DOCUMENT_FILENAME_TEMPLATE,
+        SidecarOutput::File | SidecarOutput::Index => DEFAULT_SIDECAR_FILENAME_TEMPLATE,
+    };
+    let sidecar_filename = context.render_filename(
+        options
+            .sidecar_filename
+            .as_deref()
+            .unwrap_or(default_sidecar_filename),
+    )?;
+
+    let layout = companion_uploads(
+        output,
+        &folder,
+        &filename,
+        on_collision,
+        options.shared_drive.clone(),
+        options.upload_json_result.unwrap_or_default(),
+    );
+    let pdf = Upload {
+        source: output.pdf.clone(),
+        destination: Destination::Folder {
+            folder,
+            name: filename,
+            on_collision,
+            shared_drive: options.shared_drive.clone(),
+        },
+        // The text is only indexed when asked for, the job's metadata is always attached.
+        metadata: Metadata {
+            indexable_text: None,
+            ..pdf_metadata(payload, job_id, output)
+        },
+        format: Format::AsIs,
+        role: FileRole::Pdf,
+        document: output.archive_path.clone(),
+    };
+    let sidecar_upload = |source, format| Upload {
+        source,
+        destination: Destination::Folder {
+            folder: sidecar_folder,
+            name: sidecar_filename,
+            on_collision,
+            shared_drive: options.shared_drive.clone(),
+        },
+        metadata: Default::default(),
+        format,
+        role: FileRole::Sidecar,
+        document: output.archive_path.clone(),
+    };
+    let mut uploads = match (sidecar, output.sidecar.clone()) {
+        (SidecarOutput::Index, _) => vec![Upload {
+            metadata: pdf_metadata(payload, job_id, output),
+            ..pdf
+        }],
+        // The profile turned the text extraction off.
+        (_, None) => vec![pdf],
+        (SidecarOutput::File, Some(source)) => vec![pdf, sidecar_upload(source, Format::AsIs)],
+        (SidecarOutput::GoogleDoc, Some(source)) => vec![
+            pdf,
+            sidecar_upload(source, Format::GoogleDoc { link_to: Some(0) }),
+        ],
+    };
+    uploads.extend(layout);
+    Ok(uploads)
+}
+
+/// The word coordinates and the json result go next to the pdf, named after it.
+fn companion_uploads(
+    output: &OcrOutput,
+    folder: &Utf8Path,
+    pdf_name: &str,
+    on_collision: CollisionPolicy,
+    shared_drive: Option<String>,
+    upload_result: bool,
+) -> Vec<Upload> {
+    let layout = output.layout.iter().map(|file| {
+        (
+            file.path.clone(),
+            file.format.extension(),
+            FileRole::Layout(file.format),
+        )
+    });
+    let result = output
+        .result
+        .clone()
+        .filter(|_| upload_result)
+        .map(|path| (path, "json", FileRole::Result));
+    layout
+        .chain(result)
+        .map(|(source, extension, role)| Upload {
+            source,
+            destination: Destination::Folder {
+                folder: folder.to_owned(),
+                name: Utf8Path::new(pdf_name)
+                    .with_extension(extension)
+                    .into_string(),
+                on_collision,
+                shared_drive: shared_drive.clone(),
+            },
+            metadata: Default::default(),
+            format: Format::AsIs,
+            role,
+            document: output.archive_path.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use test_case::test_case;
+    use uuid::Uuid;
+
+    use super::{
+        plan_uploads, OutputOptions, SidecarOutput, TemplateContext, UploadMode, SAMPLE_VARIABLES,
+    };
+    use crate::{
+        layout::{LayoutFile, LayoutFormat},
+        ocr::OcrOutput,
+        pdfa::Conformance,
+        profile::OutputType,
+        upload::{Destination, Format, Upload},
+        Payload,
+    };
+
+    fn payload() -> Payload {
+        Payload {
+            filename: "scan.deu.pdf".to_string(),
+            path: Utf8PathBuf::from("/Scans/scan.deu.pdf"),
+            file_url: "https://drive.google.com/uc?id=abc123".parse().unwrap(),
+            language: None,
+            file_id: None,
+            output: Default::default(),
+            profile: None,
+            options: Default::default(),
+            archive: Default::default(),
+        }
+    }
+
+    fn ocr_output() -> OcrOutput {
+        OcrOutput {
+            pdf: Utf8PathBuf::from("/tmp/ocr/scan.deu.pdf"),
+            sidecar: Some(Utf8PathBuf::from("/tmp/ocr/scan.deu.txt")),
+            language: "deu".to_string(),
+            page_count: 3,
+            engine: "ocrmypdf 15.4.0".to_string(),
+            conformance: Some(Conformance {
+                requested: OutputType::Pdfa2,
+                declared: Some("2B".to_string()),
+                valid: None,
+                validator: "xmp".to_string(),
+            }),
+            archive_path: None,
+            skip_reason: None,
+            text_layer_pages: 0,
+            layout: Vec::new(),
+            result: None,
+            quality: None,
+            review: false,
+        }
+    }
+
+    /// Uploads of the job's `outputs` for the default payload.
+    fn plan(options: OutputOptions, outputs: &[OcrOutput]) -> Vec<Upload> {
+        plan_uploads(&payload(), &options, Uuid::nil(), outputs).unwrap()
+    }
+
+    /// Where every upload goes, they all have to be uploaded into a folder.
+    fn folder_paths(uploads: &[Upload]) -> Vec<String> {
+        uploads
+            .iter()
+            .map(|upload| match &upload.destination {
+                Destination::Folder { folder, name, .. } => folder.join(name).into_string(),
+                Destination::Revision { .. } => panic!("unexpected revision upload"),
+            })
+            .collect()
+    }
+
+    #[test_case("{parent}/Done" => "/Scans/Done".to_string())]
+    #[test_case("{original_stem}-ocr.pdf" => "scan.deu-ocr.pdf".to_string())]
+    #[test_case("{language}/{page_count}" => "deu/3".to_string())]
+    #[test_case("no variables" => "no variables".to_string())]
+    fn render(template: &str) -> String {
+        TemplateContext::new(&payload(), Uuid::nil(), &ocr_output())
+            .render(template)
+            .unwrap()
+    }
+
+    #[test]
+    fn render_unknown_variable() {
+        let context = TemplateContext::new(&payload(), Uuid::nil(), &ocr_output());
+        assert!(context.render("{unknown}").is_err());
+    }
+
+    #[test]
+    fn sample_has_every_variable() {
+        let context = TemplateContext::new(&payload(), Uuid::nil(), &ocr_output());
+        let mut names = context.variables.keys().copied().collect::<Vec<_>>();
+        names.sort_unstable();
+        let mut samples = SAMPLE_VARIABLES.map(|(name, _)| name);
+        samples.sort_unstable();
+        assert_eq!(names, samples);
+    }
+
+    #[test_case(OutputOptions::default() => true)]
+    #[test_case(OutputOptions {
+        folder: Some("Archive/{year}/{month}".to_string()),
+        filename: Some("{original_stem}-{job_id}.pdf".to_string()),
+        ..Default::default()
+    } => true)]
+    #[test_case(OutputOptions { folder: Some("Archive/{yaer}".to_string()), ..Default::default() } => false)]
+    #[test_case(OutputOptions { filename: Some("{year}/{original_name}".to_string()), ..Default::default() } => false)]
+    #[test_case(OutputOptions { sidecar_filename: Some("{parent}.txt".to_string()), ..Default::default() } => false)]
+    fn validate(options: OutputOptions) -> bool {
+        options.validate().is_ok()
+    }
+
+    #[test]
+    fn plan_uploads_defaults() {
+        let uploads = plan(OutputOptions::default(), &[ocr_output()]);
+        assert_eq!(
+            folder_paths(&uploads),
+            ["/Scans/Done/scan.deu.pdf", "/Scans/Done/scan.deu.txt"]
+        );
+    }
+
+    #[test]
+    fn plan_uploads_image_input() {
+        let payload = Payload {
+            filename: "receipt.jpg".to_string(),
+            ..payload()
+        };
+        let uploads = plan_uploads(
+            &payload,
+            &OutputOptions::default(),
+            Uuid::nil(),
+            &[ocr_output()],
+        )
+        .unwrap();
+        assert_eq!(folder_paths(&uploads)[0], "/Scans/Done/receipt.pdf");
+    }
+
+    #[test]
+    fn plan_uploads_archive_documents() {
+        let document = |path: &str| OcrOutput {
+            archive_path: Some(Utf8PathBuf::from(path)),
+            ..ocr_output()
+        };
+        let options = OutputOptions {
+            sidecar: Some(SidecarOutput::GoogleDoc),
+            ..Default::default()
+        };
+        let uploads = plan(
+            options,
+            &[document("a.jpg"), document("2024/receipts/b.pdf")],
+        );
+        assert_eq!(
+            folder_paths(&uploads),
+            [
+                "/Scans/Done/a.pdf",
+                "/Scans/Done/a",
+                "/Scans/Done/2024/receipts/b.pdf",
+                "/Scans/Done/2024/receipts/b",
+            ]
+        );
+        assert!(matches!(
+            uploads[3].format,
+            Format::GoogleDoc { link_to: Some(2) }
+        ));
+    }
+
+    #[test]
+    fn plan_uploads_replace_original_archive() {
+        let options = OutputOptions {
+            mode: Some(UploadMode::ReplaceOriginal),
+            ..Default::default()
+        };
+        let outputs = [ocr_output(), ocr_output()];
+        assert!(plan_uploads(&payload(), &options, Uuid::nil(), &outputs).is_err());
+    }
+
+    #[test]
+    fn plan_uploads_replace_original() {
+        let options = OutputOptions {
+            mode: Some(UploadMode::ReplaceOriginal),
+            ..Default::default()
+        };
+        let uploads = plan(options, &[ocr_output()]);
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(
+            uploads[0].metadata.indexable_text.as_deref(),
+            ocr_output().sidecar.as_deref()
+        );
+        let Destination::Revision { file_id, .. } = &uploads[0].destination else {
+            panic!("expected a revision upload");
+        };
+        assert_eq!(file_id.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn plan_uploads_replace_original_review() {
+        let options = OutputOptions {
+            mode: Some(UploadMode::ReplaceOriginal),
+            ..Default::default()
+        };
+        let output = OcrOutput {
+            quality: Some(41.5),
+            review: true,
+            ..ocr_output()
+        };
+        assert_eq!(
+            folder_paths(&plan(options, &[output])),
+            ["/Scans/Review/scan.deu.pdf", "/Scans/Review/scan.deu.txt"]
+        );
+    }
+
+    #[test]
+    fn options_are_layered() {
+        let request = OutputOptions {
+            folder: Some("Archive".to_string()),
+            ..Default::default()
+        };
+        let key = OutputOptions {
+            folder: Some("Key".to_string()),
+            sidecar_folder: Some("Text".to_string()),
+            ..Default::default()
+        };
+        let merged = request.or(key);
+        assert_eq!(merged.folder.as_deref(), Some("Archive"));
+        assert_eq!(merged.sidecar_folder.as_deref(), Some("Text"));
+    }
+
+    #[test]
+    fn plan_uploads_index_sidecar() {
+        let options = OutputOptions {
+            sidecar: Some(SidecarOutput::Index),
+            ..Default::default()
+        };
+        let uploads = plan(options, &[ocr_output()]);
+        assert_eq!(uploads.len(), 1);
+        let app_properties = uploads[0].metadata.app_properties.as_ref().unwrap();
+        assert_eq!(app_properties["drive_ocr_job_id"], Uuid::nil().to_string());
+        assert_eq!(app_properties["drive_ocr_page_count"], "3");
+        assert_eq!(app_properties["drive_ocr_pdfa_valid"], "unverified");
+        assert!(uploads[0].metadata.description.is_some());
+    }
+
+    #[test]
+    fn plan_uploads_without_sidecar() {
+        let output = OcrOutput {
+            sidecar: None,
+            ..ocr_output()
+        };
+        assert_eq!(plan(OutputOptions::default(), &[output]).len(), 1);
+    }
+
+    #[test]
+    fn plan_uploads_layout_next_to_the_pdf() {
+        let output = OcrOutput {
+            layout: vec![
+                LayoutFile {
+                    format: LayoutFormat::Hocr,
+                    path: Utf8PathBuf::from("/tmp/ocr/scan.deu.hocr"),
+                },
+                LayoutFile {
+                    format: LayoutFormat::Alto,
+                    path: Utf8PathBuf::from("/tmp/ocr/scan.deu.xml"),
+                },
+            ],
+            ..ocr_output()
+        };
+        assert_eq!(
+            folder_paths(&plan(OutputOptions::default(), &[output])),
+            [
+                "/Scans/Done/scan.deu.pdf",
+                "/Scans/Done/scan.deu.txt",
+                "/Scans/Done/scan.deu.hocr",
+                "/Scans/Done/scan.deu.xml"
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_uploads_review_folder() {
+        let output = OcrOutput {
+            quality: Some(41.5),
+            review: true,
+            ..ocr_output()
+        };
+        let uploads = plan(OutputOptions::default(), &[output]);
+        assert_eq!(
+            folder_paths(&uploads),
+            ["/Scans/Review/scan.deu.pdf", "/Scans/Review/scan.deu.txt"]
+        );
+        let properties = uploads[0].metadata.app_properties.as_ref().unwrap();
+        assert_eq!(properties["drive_ocr_quality"], "41.5");
+        assert_eq!(properties["drive_ocr_review"], "true");
+    }
+
+    #[test_case(Some(true) => 3)]
+    #[test_case(None => 2)]
+    fn plan_uploads_json_result(upload_json_result: Option<bool>) -> usize {
+        let output = OcrOutput {
+            result: Some(Utf8PathBuf::from("/tmp/ocr/scan.deu.json")),
+            ..ocr_output()
+        };
+        let options = OutputOptions {
+            upload_json_result,
+            ..Default::default()
+        };
+        plan(options, &[output]).len()
+    }
+
+    #[test]
+    fn plan_uploads_google_doc_sidecar() {
+        let options = OutputOptions {
+            sidecar: Some(SidecarOutput::GoogleDoc),
+            ..Default::default()
+        };
+        let uploads = plan(options, &[ocr_output()]);
+        assert!(matches!(
+            uploads[1].format,
+            Format::GoogleDoc { link_to: Some(0) }
+        ));
+        assert_eq!(folder_paths(&uploads)[1], "/Scans/Done/scan.deu");
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::ErrorKind;
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{info, instrument};
+
+use crate::{limits::ResourceLimits, profile::OutputType};
+
+lazy_static! {
+    /// pikepdf writes the pdf/a identification either as xmp elements or attributes.
+    static ref PDFA_PART_REGEX: Regex =
+        Regex::new(r#"pdfaid:part(?:>|=")\s*([1-4])"#).expect("invalid regex");
+    static ref PDFA_CONFORMANCE_REGEX: Regex =
+        Regex::new(r#"pdfaid:conformance(?:>|=")\s*([ABUabu])"#).expect("invalid regex");
+}
+
+/// Outcome of checking an ocred pdf against the requested pdf/a level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conformance {
+    pub requested: OutputType,
+    /// Level the file claims in its metadata e.g. `2B`, `None` when it claims none.
+    pub declared: Option<String>,
+    /// `None` when the file declares the requested level but no validator could check it.
+    pub valid: Option<bool>,
+    /// Program that checked the file, `xmp` when only the declared level could be compared.
+    pub validator: String,
+}
+
+impl Conformance {
+    pub fn is_invalid(&self) -> bool {
+        self.valid == Some(false)
+    }
+
+    /// `true`, `false` or `unverified`.
+    pub fn status(&self) -> &'static str {
+        match self.valid {
+            Some(true) => "true",
+            Some(false) => "false",
+            None => "unverified",
+        }
+    }
+}
+
+/// Check `pdf` against the pdf/a part of `requested`, plain pdfs are not checked.
+/// veraPDF is used when it is installed, otherwise a file declaring the requested level is left
+/// unverified and one declaring another level is invalid.
+#[instrument(skip(limits), ret)]
+pub async fn validate(
+    pdf: &Utf8Path,
+    requested: OutputType,
+    limits: &ResourceLimits,
+) -> Result<Option<Conformance>> {
+    let Some(part) = requested.pdfa_part() else {
+        return Ok(None);
+    };
+    let declared = parse_declared_level(&pdfinfo_metadata(pdf, limits).await?);
+    let (valid, validator) = match verapdf(pdf, part, limits).await? {
+        Some(valid) => (Some(valid), "verapdf"),
+        None => (declared_validity(declared.as_deref(), part), "xmp"),
+    };
+    Ok(Some(Conformance {
+        requested,
+        declared,
+        valid,
+        validator: validator.to_string(),
+    }))
+}
+
+async fn pdfinfo_metadata(pdf: &Utf8Path, limits: &ResourceLimits) -> Result<String> {
+    let output = limits
+        .output(Command::new("pdfinfo").args(["-meta", pdf.as_str()]))
+        .await
+        .wrap_err("failed call spawn pdfinfo")?;
+    if !output.status.success() {
+        return Err(eyre!("failed to read the metadata of {pdf}"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `None` when veraPDF is not installed.
+async fn verapdf(pdf: &Utf8Path, part: &str, limits: &ResourceLimits) -> Result<Option<bool>> {
+    let flavour = format!("{part}b");
+    let mut command = Command::new("verapdf");
+    command.args([
+        "--format",
+        "text",
+        "--flavour",
+        flavour.as_str(),
+        pdf.as_str(),
+    ]);
+    let output = match limits.output(&mut command).await {
+        Ok(output) => output,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("veraPDF is not installed, checking the declared level only");
+            return Ok(None);
+        }
+        Err(err) => return Err(err).wrap_err("failed call spawn verapdf"),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_verapdf(&stdout)
+        .map(Some)
+        .ok_or_else(|| eyre!("unexpected verapdf output {stdout:?}"))
+}
+
+/// Without a validator a matching declared level proves nothing, only a mismatch does.
+fn declared_validity(declared: Option<&str>, part: &str) -> Option<bool> {
+    match declared {
+        Some(level) if level.starts_with(part) => None,
+        _ => Some(false),
+    }
+}
+
+fn parse_verapdf(output: &str) -> Option<bool> {
+    output.lines().find_map(|line| {
+        if line.starts_with("PASS") {
+            Some(true)
+        } else if line.starts_with("FAIL") {
+            Some(false)
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_declared_level(metadata: &str) -> Option<String> {
+    let part = PDFA_PART_REGEX.captures(metadata)?.get(1)?.as_str();
+    let conformance = PDFA_CONFORMANCE_REGEX
+        .captures(metadata)
+        .and_then(|captures| captures.get(1))
+        .map_or(String::new(), |conformance| {
+            conformance.as_str().to_uppercase()
+        });
+    Some(format!("{part}{conformance}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    #[test_case("<pdfaid:part>2</pdfaid:part>\n<pdfaid:conformance>B</pdfaid:conformance>" => Some("2B".to_string()))]
+    #[test_case(r#"<rdf:Description pdfaid:part="1" pdfaid:conformance="b"/>"# => Some("1B".to_string()))]
+    #[test_case("<pdfaid:part>3</pdfaid:part>" => Some("3".to_string()))]
+    #[test_case("<dc:title>scan</dc:title>" => None)]
+    fn parse_declared_level(metadata: &str) -> Option<String> {
+        super::parse_declared_level(metadata)
+    }
+
+    #[test_case(Some("2B"), "2" => None)]
+    #[test_case(Some("1B"), "2" => Some(false))]
+    #[test_case(None, "2" => Some(false))]
+    fn declared_validity(declared: Option<&str>, part: &str) -> Option<bool> {
+        super::declared_validity(declared, part)
+    }
+
+    #[test_case("PASS /tmp/scan.pdf 2b\n" => Some(true))]
+    #[test_case("FAIL /tmp/scan.pdf 2b\n" => Some(false))]
+    #[test_case("" => None)]
+    fn parse_verapdf(output: &str) -> Option<bool> {
+        super::parse_verapdf(output)
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt::Display;
+
+use clap::{Args, ValueEnum};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{engine::EngineKind, errors::DocumentError, layout::LayoutFormat};
+
+/// How ocrmypdf processes a document, saved as named profiles on a key and overridable per
+/// request. Every field is optional so the request options can be layered on top of the profile.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Args)]
+pub struct OcrOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Languages e.g. deu+eng used when the filename has none, detected when empty"
+    )]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Fix the orientation of rotated pages, enabled by default"
+    )]
+    pub rotate_pages: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Straighten crooked scans, enabled by default unless redoing the ocr"
+    )]
+    pub deskew: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, help = "Remove scanning artifacts before the ocr")]
+    pub clean: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_enum, help = "Kind of pdf produced, defaults to pdfa")]
+    pub output_type: Option<OutputType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=3),
+        help = "Optimization level from 0 to 3, defaults to 1"
+    )]
+    pub optimize: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with pages that already have text, defaults to auto"
+    )]
+    pub ocr_mode: Option<OcrMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, help = "Extract the text into a sidecar, enabled by default")]
+    pub sidecar: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do when the pdf does not conform to the requested pdf/a level"
+    )]
+    pub pdfa_fallback: Option<PdfaFallback>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_enum, help = "Program doing the ocr, defaults to ocrmypdf")]
+    pub engine: Option<EngineKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Files with word coordinates uploaded next to the pdf e.g. hocr,alto"
+    )]
+    pub layout: Option<Vec<LayoutFormat>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Produce a json result with the text, boxes and confidences of every page"
+    )]
+    pub json_result: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Resolution in dpi low resolution pages are upsampled to before the ocr"
+    )]
+    pub oversample: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        value_parser = parse_quality,
+        help = "Quality from 0 to 100 below which documents are uploaded for review, off by default"
+    )]
+    pub review_threshold: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Ocr documents below the review threshold again with stronger preprocessing first"
+    )]
+    pub review_retry: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(
+        long,
+        help = "Languages of the retry e.g. deu+eng, defaults to the ones of the first try"
+    )]
+    pub retry_language: Option<String>,
+}
+
+/// Resolution the pages of a low quality document are upsampled to when it is ocred again.
+const RETRY_OVERSAMPLE: u32 = 400;
+
+fn parse_quality(value: &str) -> Result<f32> {
+    let quality = value.parse::<f32>().map_err(|_| invalid_quality(value))?;
+    check_quality(quality)?;
+    Ok(quality)
+}
+
+fn check_quality(quality: f32) -> Result<()> {
+    if !(0.0..=100.0).contains(&quality) {
+        return Err(invalid_quality(quality).into());
+    }
+    Ok(())
+}
+
+fn invalid_quality(quality: impl Display) -> DocumentError {
+    DocumentError::InvalidOptions(format!("invalid quality {quality}, use 0 to 100"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputType {
+    Pdf,
+    Pdfa,
+    #[value(name = "pdfa-1")]
+    #[serde(rename = "pdfa-1")]
+    Pdfa1,
+    #[value(name = "pdfa-2")]
+    #[serde(rename = "pdfa-2")]
+    Pdfa2,
+    #[value(name = "pdfa-3")]
+    #[serde(rename = "pdfa-3")]
+    Pdfa3,
+}
+
+impl OutputType {
+    /// Part of the pdf/a standard, ocrmypdf produces pdf/a-2b when no part is given.
+    pub fn pdfa_part(&self) -> Option<&'static str> {
+        match self {
+            Self::Pdf => None,
+            Self::Pdfa1 => Some("1"),
+            Self::Pdfa | Self::Pdfa2 => Some("2"),
+            Self::Pdfa3 => Some("3"),
+        }
+    }
+
+    /// Value of ocrmypdf's `--output-type`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::Pdfa => "pdfa",
+            Self::Pdfa1 => "pdfa-1",
+            Self::Pdfa2 => "pdfa-2",
+            Self::Pdfa3 => "pdfa-3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PdfaFallback {
+    /// Fail the job, for archives that only accept pdf/a.
+    #[default]
+    Fail,
+    /// Upload the pdf anyway and record that it does not conform.
+    Pdf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OcrMode {
+    /// Look at the text of every page first, pages with good text are skipped and documents
+    /// with garbled text are forced.
+    #[default]
+    Auto,
+    /// Rasterize every page and ocr it again, existing text is lost.
+    Force,
+    /// Leave pages that already have text as they are.
+    Skip,
+    /// Replace the text layer of pages ocred before and keep their digital text.
+    Redo,
+}
+
+impl OcrOptions {
+    /// Fill every option that is not set with the one from `defaults`.
+    pub fn or(self, defaults: OcrOptions) -> Self {
+        Self {
+            language: self.language.or(defaults.language),
+            rotate_pages: self.rotate_pages.or(defaults.rotate_pages),
+            deskew: self.deskew.or(defaults.deskew),
+            clean: self.clean.or(defaults.clean),
+            output_type: self.output_type.or(defaults.output_type),
+            optimize: self.optimize.or(defaults.optimize),
+            ocr_mode: self.ocr_mode.or(defaults.ocr_mode),
+            sidecar: self.sidecar.or(defaults.sidecar),
+            pdfa_fallback: self.pdfa_fallback.or(defaults.pdfa_fallback),
+            engine: self.engine.or(defaults.engine),
+            layout: self.layout.or(defaults.layout),
+            json_result: self.json_result.or(defaults.json_result),
+            oversample: self.oversample.or(defaults.oversample),
+            review_threshold: self.review_threshold.or(defaults.review_threshold),
+            review_retry: self.review_retry.or(defaults.review_retry),
+            retry_language: self.retry_language.or(defaults.retry_language),
+        }
+    }
+
+    pub fn sidecar(&self) -> bool {
+        self.sidecar.unwrap_or(true)
+    }
+
+    pub fn json_result(&self) -> bool {
+        self.json_result.unwrap_or_default()
+    }
+
+    /// Whether a document of `quality` goes to review, one without any recognized word does.
+    pub fn needs_review(&self, quality: Option<f32>) -> bool {
+        self.review_threshold
+            .is_some_and(|threshold| quality.is_none_or(|quality| quality < threshold))
+    }
+
+    /// Options for another try at a document below the review threshold: cleaned, straightened
+    /// and upsampled pages. Redoing the ocr keeps the page images so they are not deskewed.
+    pub fn stronger(&self) -> Self {
+        let mode = self.ocr_mode.unwrap_or_default();
+        Self {
+            rotate_pages: Some(true),
+            deskew: Some(mode != OcrMode::Redo),
+            clean: Some(true),
+            oversample: Some(self.oversample.unwrap_or_default().max(RETRY_OVERSAMPLE)),
+            review_retry: Some(false),
+            ..self.clone()
+        }
+    }
+
+    /// Check the options before they are saved, an invalid profile would fail every job.
+    pub fn validate(&self) -> Result<()> {
+        self.arguments()?;
+        if let Some(threshold) = self.review_threshold {
+            check_quality(threshold)?;
+        }
+        self.check_engine()
+    }
+
+    /// The engine has to honour every option that is set, the output type included.
+    pub fn check_engine(&self) -> Result<()> {
+        let engine = self.engine.unwrap_or_default();
+        let name = engine.to_possible_value().unwrap().get_name().to_string();
+        match self.output_type {
+            Some(output_type) if output_type != OutputType::Pdf && !engine.writes_pdfa() => {
+                return Err(DocumentError::OutputType {
+                    engine: name,
+                    output_type: output_type.as_str().to_string(),
+                }
+                .into())
+            }
+            _ => {}
+        }
+        if engine.preprocesses() {
+            return Ok(());
+        }
+        // The defaults of ocrmypdf are left alone, only options asked for are refused.
+        let unsupported = [
+            ("rotate-pages", self.rotate_pages == Some(true)),
+            ("deskew", self.deskew == Some(true)),
+            ("clean", self.clean == Some(true)),
+            ("optimize", self.optimize.is_some()),
+            ("oversample", self.oversample.is_some()),
+            (
+                "ocr-mode",
+                matches!(self.ocr_mode, Some(OcrMode::Skip | OcrMode::Redo)),
+            ),
+            ("review-retry", self.review_retry == Some(true)),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(option, _)| option.to_string())
+        .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            return Err(DocumentError::UnsupportedOptions {
+                engine: name,
+                options: unsupported,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// ocrmypdf flags for these options, the language, sidecar and files are added by the caller.
+    pub fn arguments(&self) -> Result<Vec<String>> {
+        let mode = self.ocr_mode.unwrap_or_default();
+        // Auto is resolved per document before the engine runs, forcing is the safe fallback.
+        let mut arguments = vec![match mode {
+            OcrMode::Auto | OcrMode::Force => "--force-ocr",
+            OcrMode::Skip => "--skip-text",
+            OcrMode::Redo => "--redo-ocr",
+        }
+        .to_string()];
+        if self.rotate_pages.unwrap_or(true) {
+            arguments.push("--rotate-pages".to_string());
+        }
+        // ocrmypdf refuses to change the page images when it only redoes the text layer.
+        match (mode, self.deskew) {
+            (OcrMode::Redo, Some(true)) => {
+                return Err(DocumentError::InvalidOptions(
+                    "deskew can not be combined with the redo ocr mode".to_string(),
+                )
+                .into())
+            }
+            (OcrMode::Redo, _) | (_, Some(false)) => {}
+            (_, _) => arguments.push("--deskew".to_string()),
+        }
+        if self.clean.unwrap_or_default() {
+            arguments.push("--clean".to_string());
+        }
+        if let Some(output_type) = self.output_type {
+            arguments.extend([
+                "--output-type".to_string(),
+                output_type.as_str().to_string(),
+            ]);
+        }
+        if let Some(oversample) = self.oversample {
+            arguments.extend(["--oversample".to_string(), oversample.to_string()]);
+        }
+        match self.optimize {
+            Some(level @ 0..=3) => arguments.extend(["--optimize".to_string(), level.to_string()]),
+            Some(level) => {
+                return Err(DocumentError::InvalidOptions(format!(
+                    "invalid optimization level {level}, use 0 to 3"
+                ))
+                .into())
+            }
+            None => {}
+        }
+        Ok(arguments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{OcrMode, OcrOptions, OutputType};
+    use crate::engine::EngineKind;
+
+    #[test_case(OcrOptions::default() => vec!["--force-ocr", "--rotate-pages", "--deskew"])]
+    #[test_case(OcrOptions {
+        ocr_mode: Some(OcrMode::Redo),
+        clean: Some(true),
+        ..Default::default()
+    } => vec!["--redo-ocr", "--rotate-pages", "--clean"])]
+    #[test_case(OcrOptions {
+        ocr_mode: Some(OcrMode::Skip),
+        rotate_pages: Some(false),
+        deskew: Some(false),
+        output_type: Some(OutputType::Pdfa2),
+        optimize: Some(3),
+        ..Default::default()
+    } => vec!["--skip-text", "--output-type", "pdfa-2", "--optimize", "3"])]
+    #[test_case(OcrOptions::default().stronger() => vec![
+        "--force-ocr", "--rotate-pages", "--deskew", "--clean", "--oversample", "400"
+    ])]
+    #[test_case(OcrOptions {
+        ocr_mode: Some(OcrMode::Redo),
+        oversample: Some(600),
+        ..Default::default()
+    }.stronger() => vec!["--redo-ocr", "--rotate-pages", "--clean", "--oversample", "600"])]
+    fn arguments(options: OcrOptions) -> Vec<String> {
+        options.arguments().unwrap()
+    }
+
+    #[test_case(OcrOptions { ocr_mode: Some(OcrMode::Redo), deskew: Some(true), ..Default::default() })]
+    #[test_case(OcrOptions { optimize: Some(4), ..Default::default() })]
+    fn invalid_arguments(options: OcrOptions) {
+        assert!(options.arguments().is_err());
+    }
+
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), output_type: Some(OutputType::Pdfa), ..Default::default() } => false)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Fake), output_type: Some(OutputType::Pdfa2), ..Default::default() } => false)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), output_type: Some(OutputType::Pdf), ..Default::default() } => true)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), ocr_mode: Some(OcrMode::Force), deskew: Some(false), ..Default::default() } => true)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), deskew: Some(true), ..Default::default() } => false)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), ocr_mode: Some(OcrMode::Skip), ..Default::default() } => false)]
+    #[test_case(OcrOptions { engine: Some(EngineKind::Tesseract), review_retry: Some(true), ..Default::default() } => false)]
+    #[test_case(OcrOptions { output_type: Some(OutputType::Pdfa), ..Default::default() } => true)]
+    #[test_case(OcrOptions { review_threshold: Some(120.0), ..Default::default() } => false)]
+    fn validate(options: OcrOptions) -> bool {
+        options.validate().is_ok()
+    }
+
+    #[test_case(None, Some(12.0) => false)]
+    #[test_case(Some(60.0), Some(59.9) => true)]
+    #[test_case(Some(60.0), Some(60.0) => false)]
+    #[test_case(Some(60.0), None => true)]
+    fn needs_review(threshold: Option<f32>, quality: Option<f32>) -> bool {
+        let options = OcrOptions {
+            review_threshold: threshold,
+            ..Default::default()
+        };
+        options.needs_review(quality)
+    }
+
+    #[test]
+    fn request_options_win_over_the_profile() {
+        let request = OcrOptions {
+            language: Some("por".to_string()),
+            ..Default::default()
+        };
+        let profile = OcrOptions {
+            language: Some("deu".to_string()),
+            clean: Some(true),
+            ..Default::default()
+        };
+        let merged = request.or(profile);
+        assert_eq!(merged.language.as_deref(), Some("por"));
+        assert_eq!(merged.clean, Some(true));
+    }
+}
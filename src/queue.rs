@@ -15,11 +15,14 @@ use rsmq_async::{PooledRsmq, RedisBytes, RsmqConnection, RsmqMessage};
 use serde::{Deserialize, Serialize};
 use tokio::{select, task, time};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, info_span, Instrument};
+use tracing::{error, info, info_span, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
-use crate::{run_ocr_background, storage, Claim, Config, Payload};
+use crate::{
+    checkpoint::WorkArea, run_ocr_background, storage, Claim, Config, JobFailure, JobStatus,
+    Payload,
+};
 
 ///! An async trait to represent a queue system i.e. RabbitMQ, Redis, etc.
 ///! It can both send and subscribe to messages with a callback, in case of errors it is sent back to the queue.
@@ -128,7 +131,7 @@ impl Queue for Redis {
 
     async fn subscribe(
         &mut self,
-        _cancel: CancellationToken,
+        cancel: CancellationToken,
         config: Arc<Config>,
         redis: Arc<storage::Redis>,
     ) -> Result<()> {
@@ -142,8 +145,18 @@ impl Queue for Redis {
                 let queue_name = self.queue_name.clone();
                 let redis = redis.clone();
                 let config = config.clone();
+                let cancel = cancel.clone();
+                let time_to_process = self.time_to_process;
 
-                task::spawn(worker(rx, client, queue_name, redis, config))
+                task::spawn(worker(
+                    rx,
+                    client,
+                    queue_name,
+                    time_to_process,
+                    redis,
+                    config,
+                    cancel,
+                ))
             })
             .collect::<Vec<_>>();
 
@@ -162,7 +175,7 @@ impl Queue for Redis {
 
             select! {
                 _ = interval.tick() => {}
-                _ = _cancel.cancelled() => {
+                _ = cancel.cancelled() => {
                     info!("Cancelling");
                     break;
                 }
@@ -179,12 +192,17 @@ impl Queue for Redis {
     }
 }
 
+/// Jobs can run longer than `time_to_process`, their message is hidden again every half of it so
+/// it is not redelivered to another worker while the first attempt is still running. Cancelling
+/// kills the running ocr and leaves the message for the next worker to resume.
 async fn worker(
     rx: Receiver<RsmqMessage<Vec<u8>>>,
     mut client: PooledRsmq,
     queue_name: String,
+    time_to_process: u64,
     redis: Arc<storage::Redis>,
     config: Arc<Config>,
+    cancel: CancellationToken,
 ) -> Result<()> {
     while let Ok(message) = rx.recv().await {
         let deserialized: Message = serde_json::from_slice(message.message.as_slice())?;
@@ -193,25 +211,65 @@ async fn worker(
         let span = info_span!("processing message", message_id = %deserialized.id, otel.kind = ?SpanKind::Consumer);
         span.set_parent(context);
 
-        match run_ocr_background(
+        let job_id = deserialized.id;
+        let job = run_ocr_background(
+            job_id,
             deserialized.claim,
             deserialized.payload,
             config.clone(),
             redis.clone(),
+            cancel.clone(),
         )
-        .instrument(span)
-        .await
-        {
+        .instrument(span);
+        tokio::pin!(job);
+        let mut heartbeat = time::interval(Duration::from_secs(time_to_process / 2));
+        heartbeat.tick().await;
+        let result = loop {
+            select! {
+                result = &mut job => break result,
+                _ = heartbeat.tick() => {
+                    let extended = client.change_message_visibility(
+                        queue_name.as_str(),
+                        message.id.as_str(),
+                        time_to_process,
+                    );
+                    if let Err(err) = extended.await {
+                        warn!(?err, "Failed to extend the visibility of the message");
+                    }
+                }
+            }
+        };
+
+        match result {
             Ok(_) => {
                 client
                     .delete_message(queue_name.as_str(), message.id.as_str())
                     .await?;
             }
             Err(err) => {
-                error!(?err, "Error processing message");
-                client
-                    .change_message_visibility(queue_name.as_str(), message.id.as_str(), 10)
-                    .await?;
+                // The message counts how often it was received, the first attempt included.
+                let failure = JobFailure::new(&err, message.rc);
+                error!(?err, ?failure, "Error processing message");
+                let retrying = failure.retrying;
+                if let Err(err) = redis
+                    .set_job_status(job_id, &JobStatus::Failed(failure))
+                    .await
+                {
+                    warn!(?err, "Failed to save the job status");
+                }
+                if retrying {
+                    client
+                        .change_message_visibility(queue_name.as_str(), message.id.as_str(), 10)
+                        .await?;
+                } else {
+                    client
+                        .delete_message(queue_name.as_str(), message.id.as_str())
+                        .await?;
+                    // Nothing is going to resume from the files of the job.
+                    if let Err(err) = WorkArea::discard(&config.work_dir, job_id).await {
+                        warn!(?err, "Failed to remove the work area of a failed job");
+                    }
+                }
             }
         }
     }
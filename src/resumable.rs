@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use google_drive3::{
+    client::{Delegate, Retry},
+    hyper,
+    hyper::{header::RETRY_AFTER, StatusCode},
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use crate::storage::Redis;
+
+/// Drive requires chunks to be a multiple of 256KB.
+const CHUNK_SIZE: u64 = 32 * 256 * 1024;
+/// Drive requests failing this many times in a row are not retried anymore.
+const MAXIMUM_REQUEST_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(64);
+const RATE_LIMIT_REASONS: [&str; 2] = ["userRateLimitExceeded", "rateLimitExceeded"];
+
+/// Persists the session of a resumable upload so a retried job continues where the last one
+/// stopped. Sessions are keyed by the content of the file so a different file never resumes
+/// somebody else's session.
+pub(crate) struct ResumableUpload<'a> {
+    redis: &'a Redis,
+    key: String,
+}
+
+impl<'a> ResumableUpload<'a> {
+    #[instrument(skip(redis))]
+    pub(crate) async fn new(redis: &'a Redis, job_id: Uuid, source: &Utf8Path) -> Result<Self> {
+        let mut file = File::open(source)
+            .await
+            .wrap_err_with(|| format!("failed to open {source}"))?;
+        let mut hash = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hash.update(&buffer[..read]);
+        }
+        let key = format!("{job_id}_{}", hex::encode(hash.finalize()));
+        Ok(Self { redis, key })
+    }
+
+    /// A delegate resuming the saved session. The sessions drive issues are saved in the order
+    /// they come in while the upload is still running, so a worker killed mid-upload resumes it.
+    pub(crate) async fn delegate(&self) -> Result<UploadDelegate> {
+        let session_url = self.redis.get_upload_session(&self.key).await?;
+        let (sessions, mut issued) = unbounded_channel::<Option<String>>();
+        let redis = self.redis.clone();
+        let key = self.key.clone();
+        let saving = tokio::spawn(async move {
+            while let Some(url) = issued.recv().await {
+                if let Err(err) = redis.set_upload_session(&key, url.as_deref()).await {
+                    warn!(?err, "Failed to save the upload session");
+                }
+            }
+        });
+        Ok(UploadDelegate {
+            session_url,
+            attempt: 0,
+            sessions,
+            saving,
+        })
+    }
+
+    /// Wait for the sessions of `delegate` to be saved, then clear the session of a finished
+    /// upload or keep the last one of a failed upload.
+    pub(crate) async fn finish(&self, delegate: UploadDelegate, uploaded: bool) -> Result<()> {
+        let UploadDelegate {
+            session_url,
+            sessions,
+            saving,
+            ..
+        } = delegate;
+        drop(sessions);
+        saving.await?;
+        let session_url = session_url.filter(|_| !uploaded);
+        self.redis
+            .set_upload_session(&self.key, session_url.as_deref())
+            .await
+    }
+}
+
+/// Retries with exponential backoff on rate limits and server errors while keeping track of the
+/// resumable session url.
+#[derive(Debug)]
+pub(crate) struct UploadDelegate {
+    session_url: Option<String>,
+    attempt: u32,
+    /// Sessions drive issued, saved by the `saving` task.
+    sessions: UnboundedSender<Option<String>>,
+    saving: JoinHandle<()>,
+}
+
+impl UploadDelegate {
+    fn retry(&mut self, retry_after: Option<Duration>) -> Retry {
+        if self.attempt >= MAXIMUM_REQUEST_ATTEMPTS {
+            warn!(attempt = self.attempt, "Giving up on retrying");
+            return Retry::Abort;
+        }
+        let wait = retry_after.unwrap_or_else(|| backoff(self.attempt));
+        self.attempt += 1;
+        warn!(attempt = self.attempt, ?wait, "Retrying drive request");
+        Retry::After(wait)
+    }
+}
+
+impl Delegate for UploadDelegate {
+    fn http_error(&mut self, err: &hyper::Error) -> Retry {
+        warn!(?err, "Drive request failed");
+        self.retry(None)
+    }
+
+    fn upload_url(&mut self) -> Option<String> {
+        self.session_url.clone()
+    }
+
+    fn store_upload_url(&mut self, url: Option<&str>) {
+        self.session_url = url.map(String::from);
+        // The saving task only stops once the delegate is finished.
+        let _ = self.sessions.send(self.session_url.clone());
+    }
+
+    fn http_failure(
+        &mut self,
+        response: &hyper::Response<hyper::body::Body>,
+        err: Option<serde_json::Value>,
+    ) -> Retry {
+        if !is_retryable(response.status(), err.as_ref()) {
+            return Retry::Abort;
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        self.retry(retry_after)
+    }
+
+    fn chunk_size(&mut self) -> u64 {
+        CHUNK_SIZE
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAXIMUM_BACKOFF)
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Rate limits come as 429 or as 403 with a rate limit reason, server errors are transient.
+fn is_retryable(status: StatusCode, err: Option<&serde_json::Value>) -> bool {
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        return true;
+    }
+    let mut reasons = err
+        .and_then(|err| err.pointer("/error/errors"))
+        .and_then(|errors| errors.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|error| error.get("reason").and_then(|reason| reason.as_str()));
+    status == StatusCode::FORBIDDEN && reasons.any(|reason| RATE_LIMIT_REASONS.contains(&reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use google_drive3::hyper::StatusCode;
+    use serde_json::json;
+    use test_case::test_case;
+
+    #[test_case(0 => Duration::from_secs(1))]
+    #[test_case(3 => Duration::from_secs(8))]
+    #[test_case(10 => Duration::from_secs(64))]
+    fn backoff(attempt: u32) -> Duration {
+        super::backoff(attempt)
+    }
+
+    #[test_case("120" => Some(Duration::from_secs(120)))]
+    #[test_case("Wed, 21 Oct 2015 07:28:00 GMT" => None)]
+    fn parse_retry_after(value: &str) -> Option<Duration> {
+        super::parse_retry_after(value)
+    }
+
+    #[test_case(StatusCode::TOO_MANY_REQUESTS, None => true)]
+    #[test_case(StatusCode::BAD_GATEWAY, None => true)]
+    #[test_case(StatusCode::NOT_FOUND, None => false)]
+    #[test_case(StatusCode::FORBIDDEN, None => false)]
+    #[test_case(StatusCode::FORBIDDEN, Some(json!({"error": {"errors": [{"reason": "userRateLimitExceeded"}]}})) => true)]
+    #[test_case(StatusCode::FORBIDDEN, Some(json!({"error": {"errors": [{"reason": "insufficientPermissions"}]}})) => false)]
+    fn is_retryable(status: StatusCode, err: Option<serde_json::Value>) -> bool {
+        super::is_retryable(status, err.as_ref())
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{output::OutputOptions, profile::OcrOptions, storage::Redis, Config};
+
+/// Defaults bound to a generated key, they are used for every request signed with it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Args)]
+pub struct KeySettings {
+    #[serde(default)]
+    #[command(flatten)]
+    pub output: OutputOptions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, help = "Ocr profile used when the request does not pick one")]
+    pub profile: Option<String>,
+    /// Named ocr profiles of the key, edited one at a time.
+    #[serde(default)]
+    #[arg(skip)]
+    pub profiles: HashMap<String, OcrOptions>,
+    /// Language of keys saved before profiles existed, moved into the default profile.
+    #[serde(default, skip_serializing)]
+    #[arg(skip)]
+    language: Option<String>,
+}
+
+/// Profile created for keys that only had a language.
+const MIGRATED_PROFILE: &str = "default";
+
+impl KeySettings {
+    /// Fill every setting that is not set with the one from `defaults`.
+    pub fn or(self, defaults: KeySettings) -> Self {
+        let mut profiles = defaults.profiles;
+        profiles.extend(self.profiles);
+        Self {
+            output: self.output.or(defaults.output),
+            profile: self.profile.or(defaults.profile),
+            profiles,
+            language: self.language.or(defaults.language),
+        }
+    }
+
+    /// Move the language of settings saved before profiles existed into the default profile,
+    /// creating one when the key has none. A language set on the profile wins.
+    pub fn migrate(mut self) -> Self {
+        let Some(language) = self.language.take() else {
+            return self;
+        };
+        let name = self
+            .profile
+            .get_or_insert_with(|| MIGRATED_PROFILE.to_string())
+            .clone();
+        let profile = self.profiles.entry(name).or_default();
+        profile.language = profile.language.take().or(Some(language));
+        self
+    }
+
+    /// Options of the profile called `name` or of the key's default profile.
+    pub fn ocr_options(&self, name: Option<&str>) -> Result<OcrOptions> {
+        match name.or(self.profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| eyre!("unknown ocr profile {name:?}")),
+            None => Ok(OcrOptions::default()),
+        }
+    }
+}
+
+/// Merge `update` into the settings stored for `token_id`, settings that are not set are kept.
+pub async fn update_key_settings(
+    token_id: Uuid,
+    update: KeySettings,
+    config: &Config,
+) -> Result<KeySettings> {
+    let redis = Redis::from_dsn(config.redis_dsn.clone());
+    let settings = update.or(redis.get_key_settings(token_id).await?);
+    settings.ocr_options(None)?;
+    settings.output.validate()?;
+    redis.set_key_settings(token_id, &settings).await?;
+    Ok(settings)
+}
+
+/// Merge `update` into the profile called `name` of `token_id`, creating it when missing.
+pub async fn update_profile(
+    token_id: Uuid,
+    name: String,
+    update: OcrOptions,
+    config: &Config,
+) -> Result<OcrOptions> {
+    let redis = Redis::from_dsn(config.redis_dsn.clone());
+    let mut settings = redis.get_key_settings(token_id).await?;
+    let profile = update.or(settings.profiles.remove(&name).unwrap_or_default());
+    profile.validate()?;
+    settings.profiles.insert(name, profile.clone());
+    redis.set_key_settings(token_id, &settings).await?;
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeySettings;
+
+    #[test]
+    fn migrate_language_into_the_default_profile() {
+        let settings: KeySettings = serde_json::from_str(r#"{"language": "deu+eng"}"#).unwrap();
+        let settings = settings.migrate();
+        let options = settings.ocr_options(None).unwrap();
+        assert_eq!(options.language.as_deref(), Some("deu+eng"));
+        let saved = serde_json::to_value(&settings).unwrap();
+        assert_eq!(saved.get("language"), None);
+        assert_eq!(saved["profile"], "default");
+    }
+
+    #[test]
+    fn migrate_keeps_the_language_of_the_profile() {
+        let settings: KeySettings = serde_json::from_str(
+            r#"{"language": "deu", "profile": "scans", "profiles": {"scans": {"language": "por"}}}"#,
+        )
+        .unwrap();
+        let options = settings.migrate().ocr_options(None).unwrap();
+        assert_eq!(options.language.as_deref(), Some("por"));
+    }
+}
@@ -1,13 +1,51 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
 use google_drive3::oauth2::storage::{TokenInfo, TokenStorage};
 use redis::{AsyncCommands, Client};
 use sha2::Digest;
-use tracing::instrument;
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::{instrument, warn};
 use url::Url;
 use uuid::Uuid;
 
+use crate::{ocr_result::OcrResult, settings::KeySettings, JobStatus};
+
+/// How long a resolved drive folder id is trusted before walking the path again.
+const FOLDER_ID_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Drive keeps resumable sessions for a week, after that the upload has to start over anyway.
+const UPLOAD_SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Statuses are kept for a while after the job finished so they can be looked up.
+const JOB_STATUS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Locks expire on their own in case the worker holding it dies, they are extended every
+/// [LOCK_RENEWAL_INTERVAL] while it is alive.
+const LOCK_TTL: Duration = Duration::from_secs(60);
+const LOCK_RENEWAL_INTERVAL: Duration = Duration::from_secs(20);
+const LOCK_WAIT: Duration = Duration::from_secs(90);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Only delete the lock if it is still owned by whoever is releasing it.
+const RELEASE_LOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+/// Only extend the lock if it is still owned by whoever is extending it.
+const EXTEND_LOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
 trait Storage<S>: Debug + Clone {
     fn get_storage_for_uuid() -> S;
 }
@@ -26,6 +64,260 @@ impl Redis {
         let client = self.client.clone();
         RedisTokenStorage { token_id, client }
     }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn get_key_settings(&self, token_id: Uuid) -> Result<KeySettings> {
+        let value: Option<String> = self
+            .client
+            .get_async_connection()
+            .await?
+            .get(key_settings_key(token_id))
+            .await
+            .wrap_err("failed to read key settings")?;
+        match value {
+            None => Ok(KeySettings::default()),
+            Some(value) => serde_json::from_str(&value)
+                .map(KeySettings::migrate)
+                .wrap_err("invalid key settings stored"),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn set_key_settings(
+        &self,
+        token_id: Uuid,
+        settings: &KeySettings,
+    ) -> Result<()> {
+        let value = serde_json::to_string(settings)?;
+        self.client
+            .get_async_connection()
+            .await?
+            .set(key_settings_key(token_id), value)
+            .await
+            .wrap_err("failed to save key settings")
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn get_folder_id(
+        &self,
+        token_id: Uuid,
+        root_id: &str,
+        path: &Utf8Path,
+    ) -> Result<Option<String>> {
+        self.client
+            .get_async_connection()
+            .await?
+            .get(folder_id_key(token_id, root_id, path))
+            .await
+            .wrap_err("failed to read cached folder id")
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn set_folder_id(
+        &self,
+        token_id: Uuid,
+        root_id: &str,
+        path: &Utf8Path,
+        folder_id: &str,
+    ) -> Result<()> {
+        self.client
+            .get_async_connection()
+            .await?
+            .set_ex(
+                folder_id_key(token_id, root_id, path),
+                folder_id,
+                FOLDER_ID_TTL.as_secs() as usize,
+            )
+            .await
+            .wrap_err("failed to cache folder id")
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn delete_folder_id(
+        &self,
+        token_id: Uuid,
+        root_id: &str,
+        path: &Utf8Path,
+    ) -> Result<()> {
+        self.client
+            .get_async_connection()
+            .await?
+            .del(folder_id_key(token_id, root_id, path))
+            .await
+            .wrap_err("failed to delete cached folder id")
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn get_upload_session(&self, key: &str) -> Result<Option<String>> {
+        self.client
+            .get_async_connection()
+            .await?
+            .get(format!("upload_session_{key}"))
+            .await
+            .wrap_err("failed to read upload session")
+    }
+
+    /// Save the session url of a resumable upload, `None` removes it.
+    #[instrument(skip(self))]
+    pub(crate) async fn set_upload_session(&self, key: &str, url: Option<&str>) -> Result<()> {
+        let key = format!("upload_session_{key}");
+        let mut connection = self.client.get_async_connection().await?;
+        match url {
+            Some(url) => connection
+                .set_ex(key, url, UPLOAD_SESSION_TTL.as_secs() as usize)
+                .await
+                .wrap_err("failed to save upload session"),
+            None => connection
+                .del(key)
+                .await
+                .wrap_err("failed to delete upload session"),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn set_job_status(&self, job_id: Uuid, status: &JobStatus) -> Result<()> {
+        let value = serde_json::to_string(status)?;
+        self.client
+            .get_async_connection()
+            .await?
+            .set_ex(
+                format!("job_status_{job_id}"),
+                value,
+                JOB_STATUS_TTL.as_secs() as usize,
+            )
+            .await
+            .wrap_err("failed to save job status")
+    }
+
+    /// Save the ocr result of the document `index` of a job next to its status, under the key
+    /// returned.
+    #[instrument(skip(self, result))]
+    pub(crate) async fn set_job_result(
+        &self,
+        job_id: Uuid,
+        index: usize,
+        result: &OcrResult,
+    ) -> Result<String> {
+        let key = format!("job_result_{job_id}_{index}");
+        let value = serde_json::to_string(result)?;
+        self.client
+            .get_async_connection()
+            .await?
+            .set_ex::<_, _, ()>(&key, value, JOB_STATUS_TTL.as_secs() as usize)
+            .await
+            .wrap_err("failed to save job result")?;
+        Ok(key)
+    }
+
+    /// Lock the creation of the folder `path` so concurrent workers do not create it twice.
+    #[instrument(skip(self))]
+    pub(crate) async fn lock_folder_path(
+        &self,
+        token_id: Uuid,
+        root_id: &str,
+        path: &Utf8Path,
+    ) -> Result<Lock> {
+        let key = format!("{token_id}_lock_folder_{root_id}_{path}");
+        let owner = Uuid::now_v7().to_string();
+        let mut connection = self.client.get_async_connection().await?;
+        let started = Instant::now();
+        loop {
+            let acquired: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&owner)
+                .arg("NX")
+                .arg("PX")
+                .arg(LOCK_TTL.as_millis() as u64)
+                .query_async(&mut connection)
+                .await
+                .wrap_err("failed to acquire lock")?;
+            if acquired.is_some() {
+                let renewal =
+                    tokio::spawn(renew_lock(self.client.clone(), key.clone(), owner.clone()));
+                return Ok(Lock {
+                    client: self.client.clone(),
+                    key,
+                    owner,
+                    renewal,
+                });
+            }
+            if started.elapsed() > LOCK_WAIT {
+                return Err(eyre!("timed out waiting for the lock {key}"));
+            }
+            sleep(LOCK_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// A lock held on redis, it has to be released explicitly or it expires after [LOCK_TTL] once
+/// it is not renewed anymore.
+pub(crate) struct Lock {
+    client: Client,
+    key: String,
+    owner: String,
+    renewal: JoinHandle<()>,
+}
+
+impl Lock {
+    /// Failing to release only delays the next owner until the lock expires, it is logged so it
+    /// does not replace the outcome of whatever the lock protected.
+    #[instrument(skip(self), fields(key = %self.key))]
+    pub(crate) async fn release(self) {
+        self.renewal.abort();
+        let result: Result<i32> = async {
+            let mut connection = self.client.get_async_connection().await?;
+            redis::Script::new(RELEASE_LOCK_SCRIPT)
+                .key(&self.key)
+                .arg(&self.owner)
+                .invoke_async(&mut connection)
+                .await
+                .wrap_err("failed to release lock")
+        }
+        .await;
+        if let Err(err) = result {
+            warn!(?err, "Failed to release the lock, it expires on its own");
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        self.renewal.abort();
+    }
+}
+
+/// Keep extending the lock until it is released, or stop once somebody else owns it.
+async fn renew_lock(client: Client, key: String, owner: String) {
+    loop {
+        sleep(LOCK_RENEWAL_INTERVAL).await;
+        let extended: Result<i32> = async {
+            let mut connection = client.get_async_connection().await?;
+            redis::Script::new(EXTEND_LOCK_SCRIPT)
+                .key(&key)
+                .arg(&owner)
+                .arg(LOCK_TTL.as_millis() as u64)
+                .invoke_async(&mut connection)
+                .await
+                .wrap_err("failed to extend lock")
+        }
+        .await;
+        match extended {
+            Ok(0) => {
+                warn!(key, "Lost the lock before releasing it");
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => warn!(?err, key, "Failed to extend the lock"),
+        }
+    }
+}
+
+fn folder_id_key(token_id: Uuid, root_id: &str, path: &Utf8Path) -> String {
+    format!("{token_id}_folder_{root_id}_{path}")
+}
+
+fn key_settings_key(token_id: Uuid) -> String {
+    format!("{token_id}_settings")
 }
 
 pub(crate) struct RedisTokenStorage {
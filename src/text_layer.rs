@@ -0,0 +1,184 @@
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result, Section, SectionExt,
+};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::{limits::ResourceLimits, profile::OcrMode};
+
+/// Share of the characters of a page that have to be letters, digits or punctuation for its text
+/// to be trusted, fonts without a unicode mapping extract as symbols and private use characters.
+const MINIMUM_READABLE_SHARE: f64 = 0.9;
+/// Characters, besides whitespace, a page needs before its text is trusted to be the whole page
+/// and not only a digital header or footer on top of a scan.
+const MINIMUM_PAGE_CHARACTERS: usize = 100;
+/// Punctuation outside of ascii that is common in letters and invoices.
+const TYPOGRAPHIC: &str = "–—‘’“”„«»•…€£§°";
+
+/// The text layer of a pdf, one entry per page.
+#[derive(Debug)]
+pub struct TextLayer {
+    pub pages: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageText {
+    /// Nothing to extract, usually a scanned page.
+    Empty,
+    Good,
+    /// Too little text to be the whole page, usually a scan with a digital header or footer.
+    Sparse,
+    /// Text that can not be searched, the page needs to be ocred again.
+    Garbled,
+}
+
+impl TextLayer {
+    #[instrument(skip(limits))]
+    pub async fn extract(pdf: &Utf8Path, limits: &ResourceLimits) -> Result<Self> {
+        let output = limits
+            .output(Command::new("pdftotext").args(["-enc", "UTF-8", pdf.as_str(), "-"]))
+            .await
+            .wrap_err("failed call spawn pdftotext")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eyre!("failed to extract the text of {pdf}")
+                .with_section(|| output.status.to_string().header("Status code:"))
+                .with_section(|| stderr.trim().to_string().header("Stderr:")));
+        }
+        Ok(Self::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// pdftotext ends every page with a form feed.
+    fn parse(text: &str) -> Self {
+        let mut pages = text.split('\x0c').map(String::from).collect::<Vec<_>>();
+        if pages.last().is_some_and(|page| page.is_empty()) {
+            pages.pop();
+        }
+        Self { pages }
+    }
+
+    pub fn classify(&self) -> Vec<PageText> {
+        self.pages.iter().map(|page| classify(page)).collect()
+    }
+
+    /// The text in the same layout as ocrmypdf's sidecar.
+    pub fn to_sidecar(&self) -> String {
+        self.pages
+            .iter()
+            .map(|page| format!("{page}\x0c"))
+            .collect()
+    }
+
+    /// Replace the pages ocrmypdf skipped in its `sidecar`, it only leaves a note for them.
+    pub fn fill_sidecar(&self, sidecar: &str) -> String {
+        sidecar
+            .split('\x0c')
+            .enumerate()
+            .map(|(index, ocred)| match self.pages.get(index) {
+                Some(page) if classify(page) == PageText::Good => page.as_str(),
+                _ => ocred,
+            })
+            .collect::<Vec<_>>()
+            .join("\x0c")
+    }
+}
+
+fn classify(page: &str) -> PageText {
+    let characters = page.chars().filter(|c| !c.is_whitespace()).count();
+    if characters == 0 {
+        return PageText::Empty;
+    }
+    let readable = page
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_ascii_punctuation() || TYPOGRAPHIC.contains(*c))
+        .count();
+    if (readable as f64 / characters as f64) < MINIMUM_READABLE_SHARE {
+        PageText::Garbled
+    } else if characters < MINIMUM_PAGE_CHARACTERS {
+        PageText::Sparse
+    } else {
+        PageText::Good
+    }
+}
+
+/// How the pages of a document are ocred in the `mode` asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcrPlan {
+    /// Every page already has good text, the engine is not needed.
+    Skip,
+    Ocr(OcrMode),
+}
+
+/// Auto skips the pages with text unless some of it is garbled, then every page is ocred again.
+/// Pages with only a little text are ocred while keeping it, ocrmypdf would skip them otherwise,
+/// which needs forcing when `redo` is not possible with the profile's options.
+pub fn plan(mode: OcrMode, pages: &[PageText], redo: bool) -> OcrPlan {
+    let all_good = !pages.is_empty() && pages.iter().all(|page| *page == PageText::Good);
+    match mode {
+        OcrMode::Auto | OcrMode::Skip if all_good => OcrPlan::Skip,
+        OcrMode::Auto if pages.contains(&PageText::Garbled) => OcrPlan::Ocr(OcrMode::Force),
+        OcrMode::Auto if pages.contains(&PageText::Sparse) && redo => OcrPlan::Ocr(OcrMode::Redo),
+        OcrMode::Auto if pages.contains(&PageText::Sparse) => OcrPlan::Ocr(OcrMode::Force),
+        OcrMode::Auto => OcrPlan::Ocr(OcrMode::Skip),
+        mode => OcrPlan::Ocr(mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{plan, OcrPlan, PageText, TextLayer};
+    use crate::profile::OcrMode;
+
+    #[test_case("" => PageText::Empty)]
+    #[test_case(" \n\n" => PageText::Empty)]
+    #[test_case(&"Rechnung Nr. 2024-17, fällig am 1.3. – 120,00 €\n".repeat(4) => PageText::Good)]
+    #[test_case("Rechnung Nr. 2024-17, fällig am 1.3. – 120,00 €\n" => PageText::Sparse)]
+    #[test_case("Seite 1 von 3 · Muster GmbH · Hauptstraße 1 · 10115 Berlin\n" => PageText::Sparse)]
+    #[test_case("\u{e001}\u{e002}\u{e003} \u{e004}\u{e005}\n" => PageText::Garbled)]
+    fn classify(page: &str) -> PageText {
+        super::classify(page)
+    }
+
+    #[test]
+    fn parse_pages() {
+        let layer = TextLayer::parse("first\n\x0c\x0cthird\n\x0c");
+        assert_eq!(layer.pages, ["first\n", "", "third\n"]);
+    }
+
+    #[test]
+    fn fill_skipped_pages() {
+        let digital = "digital text ".repeat(10);
+        let layer = TextLayer::parse(&format!("{digital}\x0c\x0c"));
+        let sidecar = "[OCR skipped on page(s) 1]\x0cscanned text\n\x0c";
+        assert_eq!(
+            layer.fill_sidecar(sidecar),
+            format!("{digital}\x0cscanned text\n\x0c")
+        );
+    }
+
+    #[test_case(OcrMode::Auto, &[PageText::Good, PageText::Good] => OcrPlan::Skip)]
+    #[test_case(OcrMode::Auto, &[PageText::Good, PageText::Sparse] => OcrPlan::Ocr(OcrMode::Redo))]
+    #[test_case(OcrMode::Auto, &[PageText::Sparse, PageText::Garbled] => OcrPlan::Ocr(OcrMode::Force))]
+    #[test_case(OcrMode::Skip, &[PageText::Sparse] => OcrPlan::Ocr(OcrMode::Skip))]
+    #[test_case(OcrMode::Auto, &[PageText::Good, PageText::Empty] => OcrPlan::Ocr(OcrMode::Skip))]
+    #[test_case(OcrMode::Auto, &[PageText::Good, PageText::Garbled] => OcrPlan::Ocr(OcrMode::Force))]
+    #[test_case(OcrMode::Auto, &[] => OcrPlan::Ocr(OcrMode::Skip))]
+    #[test_case(OcrMode::Skip, &[PageText::Good] => OcrPlan::Skip)]
+    #[test_case(OcrMode::Redo, &[PageText::Good] => OcrPlan::Ocr(OcrMode::Redo))]
+    #[test_case(OcrMode::Force, &[PageText::Good] => OcrPlan::Ocr(OcrMode::Force))]
+    fn plan_mode(mode: OcrMode, pages: &[PageText]) -> OcrPlan {
+        plan(mode, pages, true)
+    }
+
+    #[test]
+    fn force_a_scan_with_a_footer_when_redo_is_not_possible() {
+        assert_eq!(
+            plan(OcrMode::Auto, &[PageText::Sparse], false),
+            OcrPlan::Ocr(OcrMode::Force)
+        );
+    }
+}
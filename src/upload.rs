@@ -1,26 +1,148 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    sync::Arc,
+};
 
 use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
 use color_eyre::{
     eyre::{eyre, WrapErr},
     Result,
 };
 use google_drive3::{
-    hyper, hyper::client::HttpConnector, hyper_rustls, hyper_rustls::HttpsConnector, oauth2,
-    oauth2::InstalledFlowReturnMethod, DriveHub,
+    api::{File, FileContentHints, Revision},
+    hyper,
+    hyper::client::HttpConnector,
+    hyper_rustls,
+    hyper_rustls::HttpsConnector,
+    oauth2,
+    oauth2::InstalledFlowReturnMethod,
+    DriveHub,
 };
+use sha2::{Digest, Sha256};
 use tokio::fs;
-use tracing::{info, info_span, instrument, Instrument};
+use tracing::{info, info_span, instrument, warn, Instrument};
+use url::Url;
+use uuid::Uuid;
 
-use crate::{storage::Redis, Claim, Config};
+use crate::{
+    checkpoint::{UploadedFile, WorkArea},
+    layout::LayoutFormat,
+    output::CollisionPolicy,
+    resumable::ResumableUpload,
+    storage::Redis,
+    Claim, Config,
+};
 
 const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+const DOCUMENT_MIME_TYPE: &str = "application/vnd.google-apps.document";
+
+pub const JOB_ID_PROPERTY: &str = "drive_ocr_job_id";
+const ROLE_PROPERTY: &str = "drive_ocr_role";
+const DOCUMENT_PROPERTY: &str = "drive_ocr_document";
+/// Alias drive uses for the root folder of `My Drive`.
+const MY_DRIVE_ID: &str = "root";
+/// How many `name (n).ext` are tried before giving up on finding a free name.
+const MAXIMUM_NUMBERED_NAMES: usize = 100;
+/// Drive caps `contentHints.indexableText` at 128KB.
+const MAXIMUM_INDEXABLE_TEXT_SIZE: usize = 128 * 1024;
+
+type Hub = DriveHub<HttpsConnector<HttpConnector>>;
+
+/// A local file and where it should end up on google drive.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub source: Utf8PathBuf,
+    pub destination: Destination,
+    pub metadata: Metadata,
+    pub format: Format,
+    pub role: FileRole,
+    /// Path of the document inside of the archive it came from, tells apart the files of the
+    /// documents of one job.
+    pub document: Option<Utf8PathBuf>,
+}
+
+impl Upload {
+    /// Shift the uploads `link_to` refers to, for when this upload is appended after `offset`
+    /// others.
+    pub fn offset_links(self, offset: usize) -> Self {
+        let format = match self.format {
+            Format::GoogleDoc { link_to } => Format::GoogleDoc {
+                link_to: link_to.map(|index| index + offset),
+            },
+            format => format,
+        };
+        Self { format, ..self }
+    }
+}
+
+/// What an uploaded file is to its job, used to find it again on retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileRole {
+    Pdf,
+    Sidecar,
+    Layout(LayoutFormat),
+    /// The json result of the ocr.
+    Result,
+}
+
+impl Display for FileRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileRole::Pdf => f.write_str("pdf"),
+            FileRole::Sidecar => f.write_str("sidecar"),
+            FileRole::Layout(LayoutFormat::Hocr) => f.write_str("hocr"),
+            FileRole::Layout(LayoutFormat::Alto) => f.write_str("alto"),
+            FileRole::Result => f.write_str("result"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum Format {
+    /// Upload the file's content as it is.
+    #[default]
+    AsIs,
+    /// Convert the text file into a google doc, linking back to the file uploaded at `link_to`.
+    GoogleDoc { link_to: Option<usize> },
+}
+
+/// Extra information written on the drive file besides its content.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Text file used as the drive's `contentHints.indexableText` of the uploaded file.
+    pub indexable_text: Option<Utf8PathBuf>,
+    pub app_properties: Option<HashMap<String, String>>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Destination {
+    /// Create a new file inside of `folder`, `on_collision` decides what happens when a file
+    /// with the same name already exists there.
+    Folder {
+        folder: Utf8PathBuf,
+        name: String,
+        on_collision: CollisionPolicy,
+        /// Name of the shared drive `folder` is in, `My Drive` when empty.
+        shared_drive: Option<String>,
+    },
+    /// Upload as a new revision of an existing file, looked up by `path` when the id is unknown.
+    Revision {
+        file_id: Option<String>,
+        path: Utf8PathBuf,
+        keep_previous_revision: bool,
+        shared_drive: Option<String>,
+    },
+}
 
-#[instrument(skip(config, redis))]
+#[instrument(skip(work_area, config, redis))]
 pub async fn upload_files(
+    job_id: Uuid,
     claim: Claim,
-    files: &[Utf8PathBuf],
-    upload_path: &Utf8Path,
+    uploads: &[Upload],
+    work_area: &mut WorkArea,
     config: Arc<Config>,
     redis: Arc<Redis>,
 ) -> Result<()> {
@@ -44,74 +166,655 @@ pub async fn upload_files(
         ),
         auth,
     );
-    for file in files {
-        let full_path = upload_path.join(file.file_name().unwrap_or("?"));
-        let folder_id = get_or_create_folder_id(&hub, upload_path).await?;
-        let full_path = full_path.as_str();
-        info!(full_path, "Uploading file");
-        let google_file = google_drive3::api::File {
-            name: file.file_name().map(String::from),
-            parents: Some(vec![folder_id]),
+    let mut folder_ids = HashMap::new();
+    let mut links: Vec<Option<String>> = Vec::with_capacity(uploads.len());
+    for upload in uploads {
+        let checkpoint_key = upload
+            .source
+            .strip_prefix(work_area.dir())
+            .unwrap_or(&upload.source)
+            .to_string();
+        if let Some(uploaded) = work_area.checkpoint().uploaded.get(&checkpoint_key) {
+            info!(source = %upload.source, ?uploaded, "Already uploaded on a previous attempt");
+            links.push(uploaded.web_view_link.clone());
+            continue;
+        }
+        let document = upload.document.as_deref().map(document_tag);
+        if let Some(existing) = find_tagged(&hub, job_id, upload.role, document.as_deref()).await? {
+            info!(source = %upload.source, id = ?existing.id, "Already on drive");
+            record_upload(work_area, &mut links, checkpoint_key, existing).await?;
+            continue;
+        }
+        let mut app_properties = upload.metadata.app_properties.clone().unwrap_or_default();
+        app_properties.insert(JOB_ID_PROPERTY.to_string(), job_id.to_string());
+        app_properties.insert(ROLE_PROPERTY.to_string(), upload.role.to_string());
+        if let Some(document) = document {
+            app_properties.insert(DOCUMENT_PROPERTY.to_string(), document);
+        }
+        let content_hints = match &upload.metadata.indexable_text {
+            None => None,
+            Some(path) => Some(FileContentHints {
+                indexable_text: Some(read_indexable_text(path).await?),
+                ..Default::default()
+            }),
+        };
+        let (source, mime_type) = match upload.format {
+            Format::AsIs => (upload.source.clone(), None),
+            Format::GoogleDoc { link_to } => {
+                let link = link_to.and_then(|i| links.get(i).cloned().flatten());
+                let html = write_document_html(&upload.source, link.as_deref()).await?;
+                (html, Some(DOCUMENT_MIME_TYPE.to_string()))
+            }
+        };
+        let resumable = ResumableUpload::new(&redis, job_id, &source).await?;
+        let google_file = File {
+            mime_type,
+            content_hints,
+            app_properties: Some(app_properties),
+            description: upload.metadata.description.clone(),
             ..Default::default()
         };
-        let f = fs::File::open(file).await?;
-        let c = hub.files().create(google_file);
-        let span = info_span!("upload_file");
-        span.record("filename", full_path);
-        let _ = c
-            .upload(f.into_std().await, guess_mime_from_file(file))
-            .instrument(span)
-            .await
-            .wrap_err("failed to upload file")?;
+        let uploaded = match &upload.destination {
+            Destination::Folder {
+                folder,
+                name,
+                on_collision,
+                shared_drive,
+            } => {
+                let cache_key = (shared_drive.clone(), folder.clone());
+                let folder_id = match folder_ids.get(&cache_key) {
+                    Some(folder_id) => String::from(folder_id),
+                    None => {
+                        let root_id = root_id(&hub, shared_drive.as_deref()).await?;
+                        let folder_id =
+                            resolve_folder_id(&hub, &redis, claim.token_id, &root_id, folder)
+                                .await?;
+                        folder_ids.insert(cache_key, folder_id.clone());
+                        folder_id
+                    }
+                };
+                let mut existing = find_children(&hub, &folder_id, name, None).await?;
+                if existing.len() > 1 && *on_collision == CollisionPolicy::Overwrite {
+                    return Err(eyre!(
+                        "{folder}/{name} exists {} times, not sure which one to overwrite",
+                        existing.len()
+                    ));
+                }
+                match (existing.pop(), on_collision) {
+                    (Some(existing), CollisionPolicy::Skip) => {
+                        info!(%folder, %name, "File already exists, skipping");
+                        existing
+                    }
+                    (Some(existing), CollisionPolicy::Overwrite) => {
+                        let file_id = existing
+                            .id
+                            .ok_or_else(|| eyre!("{folder}/{name} has no id"))?;
+                        info!(%folder, %name, %file_id, "File already exists, overwriting");
+                        update_file(&hub, &file_id, google_file, &source, &resumable).await?
+                    }
+                    (Some(_), CollisionPolicy::Suffix) => {
+                        let name = free_numbered_name(&hub, &folder_id, name).await?;
+                        create_file(
+                            &hub,
+                            folder,
+                            &folder_id,
+                            name,
+                            google_file,
+                            &source,
+                            &resumable,
+                        )
+                        .await?
+                    }
+                    (Some(_), CollisionPolicy::Timestamp) => {
+                        let timestamp = Utc::now().format("%Y%m%dT%H%M%S").to_string();
+                        let name = suffixed_name(name, &timestamp);
+                        create_file(
+                            &hub,
+                            folder,
+                            &folder_id,
+                            name,
+                            google_file,
+                            &source,
+                            &resumable,
+                        )
+                        .await?
+                    }
+                    (None, _) | (Some(_), CollisionPolicy::Duplicate) => {
+                        let name = name.clone();
+                        create_file(
+                            &hub,
+                            folder,
+                            &folder_id,
+                            name,
+                            google_file,
+                            &source,
+                            &resumable,
+                        )
+                        .await?
+                    }
+                }
+            }
+            Destination::Revision {
+                file_id,
+                path,
+                keep_previous_revision,
+                shared_drive,
+            } => {
+                let file_id = match file_id {
+                    Some(file_id) => file_id.clone(),
+                    None => {
+                        let root_id = root_id(&hub, shared_drive.as_deref()).await?;
+                        find_file_id(&hub, &root_id, path).await?
+                    }
+                };
+                if *keep_previous_revision {
+                    pin_head_revision(&hub, &file_id).await?;
+                }
+                info!(%path, %file_id, "Uploading new revision");
+                update_file(&hub, &file_id, google_file, &source, &resumable).await?
+            }
+        };
+        record_upload(work_area, &mut links, checkpoint_key, uploaded).await?;
+    }
+    Ok(())
+}
+
+async fn record_upload(
+    work_area: &mut WorkArea,
+    links: &mut Vec<Option<String>>,
+    checkpoint_key: String,
+    file: File,
+) -> Result<()> {
+    let uploaded = UploadedFile {
+        id: file.id,
+        web_view_link: file.web_view_link,
+    };
+    links.push(uploaded.web_view_link.clone());
+    work_area
+        .save(|checkpoint| {
+            checkpoint.uploaded.insert(checkpoint_key, uploaded);
+        })
+        .await
+}
+
+/// Find a file uploaded by an earlier attempt of the same job, so retries never upload twice.
+#[instrument(skip(hub))]
+async fn find_tagged(
+    hub: &Hub,
+    job_id: Uuid,
+    role: FileRole,
+    document: Option<&str>,
+) -> Result<Option<File>> {
+    let (_, file_list) = hub
+        .files()
+        .list()
+        .q(&tagged_query(job_id, role, document))
+        .corpora("allDrives")
+        .supports_all_drives(true)
+        .include_items_from_all_drives(true)
+        .param("fields", "files(id,name,webViewLink)")
+        .doit()
+        .await
+        .wrap_err("failed to look for files uploaded by the job")?;
+    Ok(file_list.files.and_then(|files| files.into_iter().next()))
+}
+
+/// Drive limits a property's key and value to 124 bytes, paths inside of archives can be longer.
+fn document_tag(path: &Utf8Path) -> String {
+    hex::encode(Sha256::digest(path.as_str()))
+}
+
+fn tagged_query(job_id: Uuid, role: FileRole, document: Option<&str>) -> String {
+    let document = match document {
+        Some(document) => {
+            format!("appProperties has {{ key='{DOCUMENT_PROPERTY}' and value='{document}' }} and ")
+        }
+        None => String::new(),
+    };
+    format!(
+        "appProperties has {{ key='{JOB_ID_PROPERTY}' and value='{job_id}' }} \
+         and appProperties has {{ key='{ROLE_PROPERTY}' and value='{role}' }} \
+         and {document}trashed = false"
+    )
+}
+
+/// Resolve the folder id through the cache, falling back to walking the path when the cached
+/// folder was trashed or deleted.
+#[instrument(skip(hub, redis))]
+async fn resolve_folder_id(
+    hub: &Hub,
+    redis: &Redis,
+    token_id: Uuid,
+    root_id: &str,
+    path: &Utf8Path,
+) -> Result<String> {
+    if let Some(folder_id) = redis.get_folder_id(token_id, root_id, path).await? {
+        if folder_exists(hub, &folder_id).await {
+            return Ok(folder_id);
+        }
+        info!(%folder_id, "Cached folder is gone, resolving it again");
+        redis.delete_folder_id(token_id, root_id, path).await?;
+    }
+
+    let folder_id = get_or_create_folder_id(hub, redis, token_id, root_id, path).await?;
+    redis
+        .set_folder_id(token_id, root_id, path, &folder_id)
+        .await?;
+    Ok(folder_id)
+}
+
+/// Id of the folder paths are resolved from, the shared drive's id or `My Drive`'s alias.
+#[instrument(skip(hub))]
+async fn root_id(hub: &Hub, shared_drive: Option<&str>) -> Result<String> {
+    let Some(shared_drive) = shared_drive else {
+        return Ok(MY_DRIVE_ID.to_string());
+    };
+    let query = format!("name = '{}'", escape_query(shared_drive));
+    let (_, drive_list) = hub
+        .drives()
+        .list()
+        .q(&query)
+        .doit()
+        .instrument(info_span!("find_shared_drive"))
+        .await
+        .wrap_err("failed to list shared drives")?;
+    let mut drives = drive_list.drives.unwrap_or_default();
+    match drives.len() {
+        0 => Err(eyre!("shared drive {shared_drive:?} not found")),
+        1 => drives
+            .pop()
+            .and_then(|drive| drive.id)
+            .ok_or_else(|| eyre!("shared drive {shared_drive:?} has no id")),
+        n => Err(eyre!("found {n} shared drives named {shared_drive:?}")),
+    }
+}
+
+async fn folder_exists(hub: &Hub, folder_id: &str) -> bool {
+    let result = hub
+        .files()
+        .get(folder_id)
+        .supports_all_drives(true)
+        .param("fields", "id,trashed")
+        .doit()
+        .instrument(info_span!("validate_folder_id"))
+        .await;
+    match result {
+        Ok((_, folder)) => !folder.trashed.unwrap_or_default(),
+        Err(err) => {
+            warn!(?err, folder_id, "Failed to validate cached folder id");
+            false
+        }
+    }
+}
+
+async fn create_file(
+    hub: &Hub,
+    folder: &Utf8Path,
+    folder_id: &str,
+    name: String,
+    google_file: File,
+    source: &Utf8Path,
+    resumable: &ResumableUpload<'_>,
+) -> Result<File> {
+    let full_path = folder.join(&name);
+    let full_path = full_path.as_str();
+    info!(full_path, "Uploading file");
+    let google_file = File {
+        name: Some(name),
+        parents: Some(vec![folder_id.to_string()]),
+        ..google_file
+    };
+    let f = fs::File::open(source).await?;
+    let mut delegate = resumable.delegate().await?;
+    let span = info_span!("upload_file");
+    span.record("filename", full_path);
+    let result = hub
+        .files()
+        .create(google_file)
+        .supports_all_drives(true)
+        .param("fields", "id,webViewLink")
+        .delegate(&mut delegate)
+        .upload_resumable(f.into_std().await, guess_mime_from_file(source))
+        .instrument(span)
+        .await;
+    resumable.finish(delegate, result.is_ok()).await?;
+    let (_, file) = result.wrap_err("failed to upload file")?;
+    Ok(file)
+}
+
+async fn update_file(
+    hub: &Hub,
+    file_id: &str,
+    google_file: File,
+    source: &Utf8Path,
+    resumable: &ResumableUpload<'_>,
+) -> Result<File> {
+    let f = fs::File::open(source).await?;
+    let mut delegate = resumable.delegate().await?;
+    let span = info_span!("upload_revision");
+    span.record("file_id", file_id);
+    let result = hub
+        .files()
+        .update(google_file, file_id)
+        .supports_all_drives(true)
+        .param("fields", "id,webViewLink")
+        .delegate(&mut delegate)
+        .upload_resumable(f.into_std().await, guess_mime_from_file(source))
+        .instrument(span)
+        .await;
+    resumable.finish(delegate, result.is_ok()).await?;
+    let (_, file) = result.wrap_err("failed to upload revision")?;
+    Ok(file)
+}
+
+/// Find the first `name (n).ext` that does not exist in the folder yet.
+async fn free_numbered_name(hub: &Hub, folder_id: &str, name: &str) -> Result<String> {
+    for n in 1..=MAXIMUM_NUMBERED_NAMES {
+        let candidate = suffixed_name(name, &format!("({n})"));
+        if find_children(hub, folder_id, &candidate, None)
+            .await?
+            .is_empty()
+        {
+            return Ok(candidate);
+        }
+    }
+    Err(eyre!("no free name found for {name}"))
+}
+
+/// Add `suffix` between the name and its extension, `scan.pdf` becomes `scan (1).pdf`.
+fn suffixed_name(name: &str, suffix: &str) -> String {
+    let path = Utf8Path::new(name);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{stem} {suffix}.{extension}"),
+        _ => format!("{name} {suffix}"),
+    }
+}
+
+/// Write an html version of the text file next to it, drive converts html into google docs
+/// keeping the paragraphs and page breaks.
+async fn write_document_html(text_file: &Utf8Path, link: Option<&str>) -> Result<Utf8PathBuf> {
+    let text = fs::read_to_string(text_file)
+        .await
+        .wrap_err_with(|| format!("failed to read {text_file}"))?;
+    let html_file = text_file.with_extension("html");
+    fs::write(&html_file, text_to_html(&text, link))
+        .await
+        .wrap_err_with(|| format!("failed to write {html_file}"))?;
+    Ok(html_file)
+}
+
+/// Pages are separated by form feeds and paragraphs by empty lines on ocrmypdf's sidecar.
+fn text_to_html(text: &str, link: Option<&str>) -> String {
+    let mut html = String::from("<html><body>");
+    if let Some(link) = link {
+        html.push_str(&format!(
+            "<p><a href=\"{}\">Original document</a></p>",
+            escape_html(link)
+        ));
     }
+    let pages = text.trim_end_matches('\x0c').split('\x0c');
+    for (i, page) in pages.enumerate() {
+        if i > 0 {
+            html.push_str("<p style=\"page-break-before: always\"></p>");
+        }
+        for paragraph in page.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+            let lines = paragraph.lines().map(escape_html).collect::<Vec<_>>();
+            html.push_str(&format!("<p>{}</p>", lines.join("<br>")));
+        }
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn read_indexable_text(path: &Utf8Path) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .await
+        .wrap_err_with(|| format!("failed to read indexable text {path}"))?;
+    Ok(truncate_indexable_text(text))
+}
+
+fn truncate_indexable_text(mut text: String) -> String {
+    if text.len() > MAXIMUM_INDEXABLE_TEXT_SIZE {
+        let mut end = MAXIMUM_INDEXABLE_TEXT_SIZE;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+/// Mark the current revision of `file_id` to be kept forever so a new upload does not purge it.
+#[instrument(skip(hub))]
+async fn pin_head_revision(hub: &Hub, file_id: &str) -> Result<()> {
+    let (_, file) = hub
+        .files()
+        .get(file_id)
+        .supports_all_drives(true)
+        .param("fields", "headRevisionId")
+        .doit()
+        .await
+        .wrap_err("failed to get the head revision")?;
+    let revision_id = file
+        .head_revision_id
+        .ok_or_else(|| eyre!("file {file_id} has no head revision"))?;
+    let revision = Revision {
+        keep_forever: Some(true),
+        ..Default::default()
+    };
+    hub.revisions()
+        .update(revision, file_id, &revision_id)
+        .doit()
+        .await
+        .wrap_err("failed to pin the head revision")?;
     Ok(())
 }
 
-#[instrument(skip_all)]
-pub async fn get_or_create_folder_id(
-    hub: &DriveHub<HttpsConnector<HttpConnector>>,
+/// Find the id of an existing file without creating any of the folders on the way.
+#[instrument(skip(hub))]
+async fn find_file_id(hub: &Hub, root_id: &str, path: &Utf8Path) -> Result<String> {
+    let mut parent_id = root_id.to_string();
+    for part in path.iter().filter(|p| *p != "/") {
+        let file = find_unique_child(hub, &parent_id, part, None)
+            .await?
+            .ok_or_else(|| eyre!("{part} not found while looking for {path}"))?;
+        parent_id = file
+            .id
+            .ok_or_else(|| eyre!("{part} has no id while looking for {path}"))?;
+    }
+    Ok(parent_id)
+}
+
+/// Like [find_children] but fails when more than one file matches.
+async fn find_unique_child(
+    hub: &Hub,
+    parent_id: &str,
+    name: &str,
+    mime_type: Option<&str>,
+) -> Result<Option<File>> {
+    let mut children = find_children(hub, parent_id, name, mime_type).await?;
+    match children.len() {
+        0 | 1 => Ok(children.pop()),
+        n => Err(eyre!(
+            "{name} exists {n} times in the folder {parent_id}, remove the duplicates"
+        )),
+    }
+}
+
+/// Files that are not trashed named `name` inside of `parent_id`.
+async fn find_children(
+    hub: &Hub,
+    parent_id: &str,
+    name: &str,
+    mime_type: Option<&str>,
+) -> Result<Vec<File>> {
+    let span = info_span!("find_children");
+    span.record("name", name);
+    let query = children_query(parent_id, name, mime_type);
+    let (_, file_list) = hub
+        .files()
+        .list()
+        .q(&query)
+        .corpora("allDrives")
+        .supports_all_drives(true)
+        .include_items_from_all_drives(true)
+        .param("fields", "files(id,name,mimeType,webViewLink,createdTime)")
+        .doit()
+        .instrument(span)
+        .await
+        .wrap_err("failed to find file")?;
+    Ok(file_list.files.unwrap_or_default())
+}
+
+fn children_query(parent_id: &str, name: &str, mime_type: Option<&str>) -> String {
+    let mime_type_query = mime_type
+        .map(|mime_type| format!(" and mimeType = '{}'", escape_query(mime_type)))
+        .unwrap_or_default();
+    format!(
+        "name = '{}' and '{}' in parents and trashed = false{mime_type_query}",
+        escape_query(name),
+        escape_query(parent_id)
+    )
+}
+
+/// Escape a value used inside of a single quoted string on drive's query language.
+fn escape_query(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[instrument(skip(hub, redis))]
+async fn get_or_create_folder_id(
+    hub: &Hub,
+    redis: &Redis,
+    token_id: Uuid,
+    root_id: &str,
     path: &Utf8Path,
 ) -> Result<String> {
-    let mut parent_id: Option<String> = Some("root".to_string());
+    let mut parent_id = root_id.to_string();
+    let mut prefix = Utf8PathBuf::new();
     for part in path.iter().filter(|p| *p != "/") {
-        let span = info_span!("get_folder_id");
-        span.record("folder_name", part);
-        let parent_id_query = parent_id
-            .as_ref()
-            .map(|id| format!(" and '{id}' in parents"))
-            .unwrap_or_default();
-        let query = format!("name='{part}' and mimeType='{FOLDER_MIME_TYPE}' {parent_id_query}");
-        let (_, file_list) = hub
-            .files()
-            .list()
-            .q(&query)
-            .doit()
-            .instrument(span)
-            .await
-            .wrap_err("failed to get folder id")?;
-
-        let folder = if let Some(folder) = file_list.files.and_then(|f| f.into_iter().next()) {
-            folder
-        } else {
-            create_folder(hub, part, parent_id.as_deref()).await?
+        prefix.push(part);
+        let folder = match find_unique_child(hub, &parent_id, part, Some(FOLDER_MIME_TYPE)).await? {
+            Some(folder) => folder,
+            None => {
+                create_locked_folder(hub, redis, token_id, root_id, &prefix, &parent_id).await?
+            }
         };
-        parent_id = folder.id;
+        parent_id = folder
+            .id
+            .ok_or_else(|| eyre!("folder {part} has no id while resolving {path}"))?;
     }
-    match parent_id {
-        None => Err(eyre!("failed to get folder id for {:?}", path)),
-        Some(parent_id) => Ok(parent_id),
+    Ok(parent_id)
+}
+
+/// Create the last folder of `prefix` holding a lock on it, jobs whose paths share the prefix
+/// wait for each other instead of creating it twice.
+#[instrument(skip(hub, redis))]
+async fn create_locked_folder(
+    hub: &Hub,
+    redis: &Redis,
+    token_id: Uuid,
+    root_id: &str,
+    prefix: &Utf8Path,
+    parent_id: &str,
+) -> Result<File> {
+    let folder_name = prefix
+        .file_name()
+        .ok_or_else(|| eyre!("{prefix} has no folder name"))?;
+    let lock = redis.lock_folder_path(token_id, root_id, prefix).await?;
+    let result = async {
+        // Whoever held the lock before might have just created the folder.
+        if let Some(folder) =
+            find_unique_child(hub, parent_id, folder_name, Some(FOLDER_MIME_TYPE)).await?
+        {
+            return Ok(folder);
+        }
+        let folder = create_folder(hub, folder_name, parent_id).await?;
+        // Drive's listing can lag behind the creation, duplicates are still settled.
+        settle_created_folder(hub, folder_name, parent_id, folder).await
     }
+    .await;
+    lock.release().await;
+    result
 }
 
-#[instrument(skip(hub))]
-async fn create_folder(
-    hub: &DriveHub<HttpsConnector<HttpConnector>>,
+/// Another process may have created the same folder at the same time, when that happens
+/// everybody keeps the oldest one. The newer duplicate is deleted when it is empty, one that
+/// already got files is left alone so nothing uploaded into it is lost.
+#[instrument(skip(hub, created))]
+async fn settle_created_folder(
+    hub: &Hub,
     folder_name: &str,
-    parent_id: Option<&str>,
-) -> Result<google_drive3::api::File> {
-    let google_file = google_drive3::api::File {
+    parent_id: &str,
+    created: File,
+) -> Result<File> {
+    let folders = find_children(hub, parent_id, folder_name, Some(FOLDER_MIME_TYPE)).await?;
+    if folders.len() <= 1 {
+        return Ok(created);
+    }
+    let oldest = oldest_file(folders).ok_or_else(|| eyre!("no folder found"))?;
+    if oldest.id != created.id {
+        if let Some(created_id) = created.id.as_deref() {
+            warn!(
+                created_id,
+                oldest_id = oldest.id.as_deref(),
+                "Folder was created concurrently, keeping the oldest"
+            );
+            // Deleting is permanent, it must not take files of other jobs with it.
+            if has_children(hub, created_id).await? {
+                warn!(created_id, "Keeping the duplicated folder, it is not empty");
+            } else {
+                hub.files()
+                    .delete(created_id)
+                    .supports_all_drives(true)
+                    .doit()
+                    .await
+                    .wrap_err("failed to delete duplicated folder")?;
+            }
+        }
+    }
+    Ok(oldest)
+}
+
+#[instrument(skip(hub))]
+async fn has_children(hub: &Hub, folder_id: &str) -> Result<bool> {
+    let (_, file_list) = hub
+        .files()
+        .list()
+        .q(&format!(
+            "'{}' in parents and trashed = false",
+            escape_query(folder_id)
+        ))
+        .corpora("allDrives")
+        .supports_all_drives(true)
+        .include_items_from_all_drives(true)
+        .page_size(1)
+        .param("fields", "files(id)")
+        .doit()
+        .await
+        .wrap_err("failed to list the folder")?;
+    Ok(!file_list.files.unwrap_or_default().is_empty())
+}
+
+/// Pick the oldest file, using the id to break ties so every process picks the same one.
+fn oldest_file(files: Vec<File>) -> Option<File> {
+    files
+        .into_iter()
+        .min_by_key(|file| (file.created_time, file.id.clone()))
+}
+
+#[instrument(skip(hub))]
+async fn create_folder(hub: &Hub, folder_name: &str, parent_id: &str) -> Result<File> {
+    let google_file = File {
         name: Some(folder_name.to_string()),
-        parents: parent_id.map(|parent_id| vec![parent_id.to_string()]),
+        parents: Some(vec![parent_id.to_string()]),
         mime_type: Some(FOLDER_MIME_TYPE.to_string()),
         ..Default::default()
     };
@@ -120,7 +823,8 @@ async fn create_folder(
     span.record("folder_name", folder_name);
     let (_, folder) = hub
         .files()
-        .create(google_file.clone())
+        .create(google_file)
+        .supports_all_drives(true)
         .upload(f.into_std().await, FOLDER_MIME_TYPE.parse()?)
         .instrument(span)
         .await
@@ -128,10 +832,28 @@ async fn create_folder(
     Ok(folder)
 }
 
+/// Extract the file id from drive urls like `https://drive.google.com/file/d/<id>/view`
+/// or `https://drive.google.com/uc?id=<id>`.
+pub fn file_id_from_url(url: &Url) -> Option<String> {
+    if url.host_str() != Some("drive.google.com") && url.host_str() != Some("docs.google.com") {
+        return None;
+    }
+    if let Some((_, id)) = url.query_pairs().find(|(key, _)| key == "id") {
+        return Some(id.into_owned());
+    }
+    let mut segments = url.path_segments()?;
+    segments.find(|segment| *segment == "d")?;
+    segments.next().map(String::from)
+}
+
 pub fn guess_mime_from_file(file: &Utf8Path) -> mime::Mime {
     match file.extension() {
         Some("pdf") => mime::APPLICATION_PDF,
         Some("txt") => mime::TEXT_PLAIN,
+        Some("html") => mime::TEXT_HTML,
+        Some("hocr") => "text/vnd.hocr+html".parse().unwrap(),
+        Some("xml") => mime::TEXT_XML,
+        Some("json") => mime::APPLICATION_JSON,
         _ => mime::APPLICATION_OCTET_STREAM,
     }
 }
@@ -143,8 +865,81 @@ mod tests {
 
     #[test_case("a.pdf" => mime::APPLICATION_PDF)]
     #[test_case("a.txt" => mime::TEXT_PLAIN)]
+    #[test_case("a.html" => mime::TEXT_HTML)]
+    #[test_case("a.hocr" => "text/vnd.hocr+html".parse::<mime::Mime>().unwrap())]
+    #[test_case("a.xml" => mime::TEXT_XML)]
+    #[test_case("a.json" => mime::APPLICATION_JSON)]
     #[test_case("a.ogg" => mime::APPLICATION_OCTET_STREAM)]
     fn guess_mime_from_file(input: &str) -> mime::Mime {
         super::guess_mime_from_file(&Utf8PathBuf::from(input))
     }
+
+    #[test_case("https://drive.google.com/file/d/abc123/view?usp=sharing" => Some("abc123".to_string()))]
+    #[test_case("https://drive.google.com/uc?id=abc123&export=download" => Some("abc123".to_string()))]
+    #[test_case("https://drive.google.com/drive/folders" => None)]
+    #[test_case("https://example.com/file/d/abc123" => None)]
+    fn file_id_from_url(input: &str) -> Option<String> {
+        super::file_id_from_url(&input.parse().unwrap())
+    }
+
+    #[test_case("one\ntwo\n\nthree", None => "<html><body><p>one<br>two</p><p>three</p></body></html>".to_string())]
+    #[test_case("a < b\x0cpage two\x0c", None => "<html><body><p>a &lt; b</p><p style=\"page-break-before: always\"></p><p>page two</p></body></html>".to_string())]
+    #[test_case("text", Some("https://drive.google.com/file/d/1") => "<html><body><p><a href=\"https://drive.google.com/file/d/1\">Original document</a></p><p>text</p></body></html>".to_string())]
+    fn text_to_html(text: &str, link: Option<&str>) -> String {
+        super::text_to_html(text, link)
+    }
+
+    #[test_case("it's", "root", None => "name = 'it\\'s' and 'root' in parents and trashed = false".to_string())]
+    #[test_case("back\\slash", "root", None => "name = 'back\\\\slash' and 'root' in parents and trashed = false".to_string())]
+    #[test_case("Done", "abc", Some(crate::upload::FOLDER_MIME_TYPE) => "name = 'Done' and 'abc' in parents and trashed = false and mimeType = 'application/vnd.google-apps.folder'".to_string())]
+    fn children_query(name: &str, parent_id: &str, mime_type: Option<&str>) -> String {
+        super::children_query(parent_id, name, mime_type)
+    }
+
+    #[test]
+    fn tagged_query() {
+        assert_eq!(
+            super::tagged_query(uuid::Uuid::nil(), super::FileRole::Sidecar, None),
+            "appProperties has { key='drive_ocr_job_id' and value='00000000-0000-0000-0000-000000000000' } \
+             and appProperties has { key='drive_ocr_role' and value='sidecar' } \
+             and trashed = false"
+        );
+        assert_eq!(
+            super::tagged_query(uuid::Uuid::nil(), super::FileRole::Pdf, Some("abc")),
+            "appProperties has { key='drive_ocr_job_id' and value='00000000-0000-0000-0000-000000000000' } \
+             and appProperties has { key='drive_ocr_role' and value='pdf' } \
+             and appProperties has { key='drive_ocr_document' and value='abc' } and trashed = false"
+        );
+    }
+
+    #[test_case("scan.pdf", "(1)" => "scan (1).pdf".to_string())]
+    #[test_case("scan.deu.pdf", "20240101T000000" => "scan.deu 20240101T000000.pdf".to_string())]
+    #[test_case("scan", "(2)" => "scan (2)".to_string())]
+    fn suffixed_name(name: &str, suffix: &str) -> String {
+        super::suffixed_name(name, suffix)
+    }
+
+    #[test]
+    fn oldest_file() {
+        let file = |id: &str, created_time: &str| google_drive3::api::File {
+            id: Some(id.to_string()),
+            created_time: Some(created_time.parse().unwrap()),
+            ..Default::default()
+        };
+        let files = vec![
+            file("c", "2024-01-01T00:00:02Z"),
+            file("b", "2024-01-01T00:00:01Z"),
+            file("a", "2024-01-01T00:00:01Z"),
+        ];
+        let oldest = super::oldest_file(files).unwrap();
+        assert_eq!(oldest.id.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn truncate_indexable_text() {
+        let text = "ä".repeat(super::MAXIMUM_INDEXABLE_TEXT_SIZE);
+        let truncated = super::truncate_indexable_text(text);
+        assert!(truncated.len() <= super::MAXIMUM_INDEXABLE_TEXT_SIZE);
+        assert!(truncated.chars().all(|c| c == 'ä'));
+    }
 }
@@ -2,13 +2,18 @@ use std::sync::Arc;
 
 use color_eyre::Result;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{ocr::LANGUAGE_REGEX, queue, queue::Queue, storage, Config};
+use crate::{
+    checkpoint::remove_stale_work_areas, ocr::LANGUAGE_REGEX, queue, queue::Queue, storage, Config,
+};
 
 pub async fn worker(config: Config, cancel: CancellationToken) -> Result<()> {
     let _ = &*LANGUAGE_REGEX; // Just fail fast in the regex is broken
     let storage = storage::Redis::from_dsn(config.redis_dsn.clone());
+    if let Err(err) = remove_stale_work_areas(&config.work_dir).await {
+        warn!(?err, "Failed to remove stale work areas");
+    }
     let mut worker = queue::Redis::new(&config).await?;
     info!("Worker started");
     worker